@@ -0,0 +1,366 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Write as _,
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use frame_analyzer::{
+    AnalyzerBuilder, FrameEvent, FrameStats, JankConfig, JankDetector, JankKind, Pid, Window,
+};
+
+use crate::{
+    jank_config,
+    stop::{Stop, parse_duration},
+    target::{self, Target},
+};
+
+// the current fps is of the last second, the 1% low of the last 30
+const CURRENT_WINDOW: Duration = Duration::from_secs(1);
+const LOW_WINDOW: Duration = Duration::from_secs(30);
+// frametimes kept for the sparkline, wider terminals show more of them
+const HISTORY: usize = 512;
+const BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+// name, pid, fps, avg, 1% low, janks and big janks, then the sparkline
+const NAME_WIDTH: usize = 24;
+const FIXED_WIDTH: usize = NAME_WIDTH + 8 + 7 + 7 + 7 + 6 + 5 + 2;
+const MIN_SPARKLINE: usize = 16;
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const DIM: &str = "\x1b[2m";
+const REVERSE: &str = "\x1b[7m";
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const CLEAR_LINE: &str = "\x1b[K";
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Pids or package names
+    #[arg(required = true)]
+    targets: Vec<String>,
+    /// How often the screen is redrawn
+    #[arg(short, long, default_value = "250ms", value_parser = parse_duration)]
+    refresh: Duration,
+    /// Stop after this long (e.g. 30s, 5m), Ctrl+C stops anyway
+    #[arg(short, long, value_parser = parse_duration)]
+    duration: Option<Duration>,
+    /// Also count the frames missing this refresh rate as janky
+    #[arg(long)]
+    target_fps: Option<u32>,
+}
+
+struct App {
+    target: Target,
+    current: FrameStats,
+    recent: FrameStats,
+    jank: JankDetector,
+    // newest last
+    history: VecDeque<(Duration, Option<JankKind>)>,
+    last_frame: Option<Instant>,
+    exited: bool,
+}
+
+impl App {
+    fn new(target: Target, jank: JankConfig) -> Self {
+        Self {
+            target,
+            current: FrameStats::new(Window::Time(CURRENT_WINDOW)),
+            recent: FrameStats::new(Window::Time(LOW_WINDOW)),
+            jank: JankDetector::new(jank),
+            history: VecDeque::with_capacity(HISTORY),
+            last_frame: None,
+            exited: false,
+        }
+    }
+
+    fn push(&mut self, event: &FrameEvent) {
+        self.last_frame = Some(Instant::now());
+        self.current.push_event(event);
+        self.recent.push_event(event);
+
+        let Some(jank) = self.jank.push_event(event) else {
+            return;
+        };
+        if self.history.len() >= HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back((event.frametime, jank.kind));
+    }
+
+    /// The fps of the last second, 0 once the app stopped drawing
+    fn fps(&self) -> f64 {
+        let drawing = self
+            .last_frame
+            .is_some_and(|last_frame| last_frame.elapsed() < CURRENT_WINDOW);
+        if drawing {
+            self.current.average_fps().unwrap_or_default()
+        } else {
+            0.0
+        }
+    }
+
+    /// The fps of the whole session
+    fn average_fps(&self) -> f64 {
+        let totals = self.jank.totals();
+        if totals.total_time.is_zero() {
+            return 0.0;
+        }
+
+        totals.frames as f64 / totals.total_time.as_secs_f64()
+    }
+
+    fn low(&self) -> f64 {
+        self.recent.low(1.0).unwrap_or_default()
+    }
+}
+
+// the alternate screen with a hidden cursor, left again on drop
+struct Screen {
+    frame: String,
+    line: String,
+}
+
+impl Screen {
+    fn enter() -> Result<Self> {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(b"\x1b[?1049h\x1b[?25l\x1b[2J")?;
+        stdout.flush()?;
+
+        Ok(Self {
+            frame: String::new(),
+            line: String::new(),
+        })
+    }
+
+    fn draw(&mut self, apps: &BTreeMap<Pid, App>, elapsed: Duration) -> io::Result<()> {
+        let (columns, rows) = terminal_size();
+        // the room the other columns leave so rows don't wrap, none if that's too narrow to read
+        let sparkline = Some(columns.saturating_sub(FIXED_WIDTH))
+            .filter(|width| *width >= MIN_SPARKLINE)
+            .unwrap_or_default();
+        let frametime = if sparkline > 0 { "FRAMETIME" } else { "" };
+        let frame = &mut self.frame;
+        let line = &mut self.line;
+        frame.clear();
+
+        let plural = if apps.len() == 1 { "" } else { "s" };
+        let title = format!(
+            " frame-analyzer  {} app{plural}  {}  Ctrl+C to quit",
+            apps.len(),
+            clock(elapsed)
+        );
+        frame.push_str("\x1b[H");
+        line.clear();
+        let _ = write!(line, "{REVERSE}{title:<columns$}");
+        push_line(frame, line, columns);
+        line.clear();
+        let _ = write!(
+            line,
+            "{BOLD}{:<NAME_WIDTH$} {:>7} {:>6} {:>6} {:>6} {:>5} {:>4}  {frametime}",
+            "NAME", "PID", "FPS", "AVG", "1%LOW", "JANK", "BIG"
+        );
+        push_line(frame, line, columns);
+
+        // the title and the header take two lines
+        for app in apps.values().take(rows.saturating_sub(2)) {
+            let totals = app.jank.totals();
+            let name = truncate(&app.target.name, NAME_WIDTH);
+            let dim = if app.exited { DIM } else { "" };
+            let janks = if totals.janks > 0 { RED } else { "" };
+
+            line.clear();
+            let _ = write!(
+                line,
+                "{dim}{name:<NAME_WIDTH$} {:>7} {:>6.1} {:>6.1} {:>6.1} {janks}{:>5}{RESET}{dim} {:>4}  ",
+                app.target.pid,
+                app.fps(),
+                app.average_fps(),
+                app.low(),
+                totals.janks,
+                totals.big_janks,
+            );
+            draw_sparkline(line, &app.history, sparkline);
+            push_line(frame, line, columns);
+        }
+        // whatever the previous frame drew below
+        frame.push_str("\x1b[J");
+
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(frame.as_bytes())?;
+        stdout.flush()
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let mut stdout = io::stdout().lock();
+        let _ = stdout.write_all(b"\x1b[?25h\x1b[?1049l");
+        let _ = stdout.flush();
+    }
+}
+
+/// Redraw a row per app until stopped, then print a summary of the session
+pub fn run(builder: AnalyzerBuilder, args: &Args) -> Result<()> {
+    let targets = target::resolve(&args.targets)?;
+    let mut analyzer = builder.build()?;
+    let mut apps = BTreeMap::new();
+    for target in targets {
+        analyzer
+            .attach_app(target.pid)
+            .with_context(|| format!("failed to attach to {} ({})", target.pid, target.name))?;
+        apps.insert(target.pid, App::new(target, jank_config(args.target_fps)));
+    }
+
+    let stop = Stop::new(args.duration)?;
+    let start = Instant::now();
+    let mut screen = Screen::enter()?;
+    let mut next = start;
+
+    while !stop.stopped() {
+        let timeout = next.saturating_duration_since(Instant::now());
+        let event = analyzer.recv_event_timeout(timeout);
+        if let Some((event, app)) = event.and_then(|event| Some((event, apps.get_mut(&event.pid)?)))
+        {
+            app.push(&event);
+        }
+
+        if Instant::now() < next {
+            continue;
+        }
+        next = Instant::now() + args.refresh;
+
+        for app in apps.values_mut() {
+            if !app.exited && !target::alive(app.target.pid) {
+                app.exited = true;
+                let _ = analyzer.detach_app(app.target.pid);
+            }
+        }
+        screen.draw(&apps, start.elapsed())?;
+    }
+
+    drop(screen);
+    for app in apps.values() {
+        let totals = app.jank.totals();
+        println!(
+            "{} {}: {:.1} fps average, {:.1} 1% low, {} janks ({} big)",
+            app.target.pid,
+            app.target.name,
+            app.average_fps(),
+            app.low(),
+            totals.janks,
+            totals.big_janks
+        );
+    }
+
+    Ok(())
+}
+
+// the newest frametimes that fit, janks in yellow and big janks in red
+//
+// Scaled to the longest of them but at least twice their median, so steady frames sit halfway and spikes stand out
+fn draw_sparkline(
+    frame: &mut String,
+    history: &VecDeque<(Duration, Option<JankKind>)>,
+    width: usize,
+) {
+    let shown = history.range(history.len().saturating_sub(width)..);
+    let mut sorted: Vec<_> = shown.clone().map(|(frametime, _)| *frametime).collect();
+    sorted.sort_unstable();
+    let (Some(median), Some(longest)) = (sorted.get(sorted.len() / 2), sorted.last()) else {
+        return;
+    };
+    let scale = longest.max(&(*median * 2)).as_secs_f64().max(f64::EPSILON);
+
+    let mut color = "";
+    for (frametime, kind) in shown {
+        let next = match kind {
+            Some(JankKind::BigJank) => RED,
+            Some(JankKind::Jank) => YELLOW,
+            None => "",
+        };
+        if next != color {
+            frame.push_str(RESET);
+            frame.push_str(next);
+            color = next;
+        }
+
+        let level = (frametime.as_secs_f64() / scale * BLOCKS.len() as f64).ceil() as usize;
+        frame.push(BLOCKS[level.clamp(1, BLOCKS.len()) - 1]);
+    }
+}
+
+// `line` cut to `columns` so it doesn't wrap, escape sequences don't take room on the screen
+fn push_line(frame: &mut String, line: &str, columns: usize) {
+    let mut shown = 0;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // `ESC [`, parameters, then a final byte in `@..=~`
+            frame.push(c);
+            frame.extend(chars.next());
+            for c in chars.by_ref() {
+                frame.push(c);
+                if ('@'..='~').contains(&c) {
+                    break;
+                }
+            }
+        } else if shown < columns {
+            frame.push(c);
+            shown += 1;
+        }
+    }
+
+    let _ = write!(frame, "{RESET}{CLEAR_LINE}\r\n");
+}
+
+fn truncate(name: &str, width: usize) -> String {
+    if name.chars().count() <= width {
+        return name.to_string();
+    }
+
+    let mut name: String = name.chars().take(width - 1).collect();
+    name.push('…');
+    name
+}
+
+fn clock(elapsed: Duration) -> String {
+    let seconds = elapsed.as_secs();
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+// columns and rows, 80x24 if stdout isn't a terminal
+fn terminal_size() -> (usize, usize) {
+    let mut size = unsafe { std::mem::zeroed::<libc::winsize>() };
+    let result = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &raw mut size) };
+
+    if result == 0 && size.ws_col > 0 && size.ws_row > 0 {
+        (usize::from(size.ws_col), usize::from(size.ws_row))
+    } else {
+        (80, 24)
+    }
+}
@@ -0,0 +1,250 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{ffi::CStr, fs, path::Path};
+
+use anyhow::Result;
+use frame_analyzer::AnalyzerBuilder;
+use serde_json::json;
+
+use crate::exit::Failure;
+
+// ring buffer maps came with linux 5.8
+const MIN_KERNEL: (u32, u32) = (5, 8);
+const TRACE_MARKERS: [&str; 2] = [
+    "/sys/kernel/tracing/trace_marker",
+    "/sys/kernel/debug/tracing/trace_marker",
+];
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Print JSON instead of text
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Ok,
+    /// Works, but some feature won't
+    Warn,
+    Fail,
+}
+
+impl Status {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Ok => "ok",
+            Self::Warn => "warn",
+            Self::Fail => "fail",
+        }
+    }
+}
+
+struct Check {
+    name: &'static str,
+    status: Status,
+    detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, status: Status, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Check the environment, failing if the analyzer can't run
+pub fn run(builder: AnalyzerBuilder, args: &Args) -> Result<()> {
+    let probes: Vec<String> = builder
+        .config()
+        .probes
+        .iter()
+        .map(|probe| probe.library.clone())
+        .collect();
+
+    let mut checks = vec![root(), architecture(), kernel(), btf()];
+    checks.extend(libraries(&probes));
+    checks.push(trace_marker());
+    // loads and attaches the ebpf program, so last
+    checks.push(attach(builder));
+
+    if args.json {
+        let checks: Vec<_> = checks
+            .iter()
+            .map(|check| {
+                json!({
+                    "check": check.name,
+                    "status": check.status.name(),
+                    "detail": check.detail,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::Value::from(checks));
+    } else {
+        for check in &checks {
+            println!(
+                "{:<5} {:<14} {}",
+                check.status.name(),
+                check.name,
+                check.detail
+            );
+        }
+    }
+
+    let failed = checks
+        .iter()
+        .filter(|check| check.status == Status::Fail)
+        .count();
+    if failed > 0 {
+        return Err(Failure::ChecksFailed(failed).into());
+    }
+    Ok(())
+}
+
+fn root() -> Check {
+    let euid = unsafe { libc::geteuid() };
+    if euid == 0 {
+        Check::new("root", Status::Ok, "running as root")
+    } else {
+        Check::new(
+            "root",
+            Status::Fail,
+            format!("running as uid {euid}, loading ebpf programs needs root"),
+        )
+    }
+}
+
+fn architecture() -> Check {
+    let machine = uname().map_or_else(String::new, |(_, machine)| machine);
+    if cfg!(target_pointer_width = "64") && (machine.is_empty() || machine.contains("64")) {
+        Check::new("architecture", Status::Ok, machine)
+    } else {
+        Check::new(
+            "architecture",
+            Status::Fail,
+            format!("{machine}, only 64-bit devices and apps are supported"),
+        )
+    }
+}
+
+fn kernel() -> Check {
+    let Some((release, _)) = uname() else {
+        return Check::new("kernel", Status::Warn, "unknown kernel version");
+    };
+
+    let mut numbers = release
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|number| number.parse::<u32>().unwrap_or_default());
+    let version = (
+        numbers.next().unwrap_or_default(),
+        numbers.next().unwrap_or_default(),
+    );
+
+    if version >= MIN_KERNEL {
+        Check::new("kernel", Status::Ok, release)
+    } else {
+        Check::new(
+            "kernel",
+            Status::Fail,
+            format!(
+                "{release}, ebpf ring buffers need {}.{} or newer",
+                MIN_KERNEL.0, MIN_KERNEL.1
+            ),
+        )
+    }
+}
+
+fn btf() -> Check {
+    if Path::new("/sys/kernel/btf/vmlinux").exists() {
+        Check::new("btf", Status::Ok, "/sys/kernel/btf/vmlinux")
+    } else {
+        Check::new(
+            "btf",
+            Status::Warn,
+            "no /sys/kernel/btf/vmlinux, relocations may fail",
+        )
+    }
+}
+
+fn libraries(libraries: &[String]) -> Vec<Check> {
+    let mut libraries = libraries.to_vec();
+    libraries.dedup();
+
+    libraries
+        .into_iter()
+        .map(|library| {
+            if Path::new(&library).exists() {
+                Check::new("probe library", Status::Ok, library)
+            } else {
+                Check::new(
+                    "probe library",
+                    Status::Fail,
+                    format!("{library} doesn't exist"),
+                )
+            }
+        })
+        .collect()
+}
+
+fn trace_marker() -> Check {
+    TRACE_MARKERS
+        .iter()
+        .find(|path| fs::OpenOptions::new().write(true).open(path).is_ok())
+        .map_or_else(
+            || {
+                Check::new(
+                    "trace marker",
+                    Status::Warn,
+                    "no writable trace_marker, trace markers are off",
+                )
+            },
+            |path| Check::new("trace marker", Status::Ok, *path),
+        )
+}
+
+fn attach(builder: AnalyzerBuilder) -> Check {
+    // the probes filter on the pid, so attaching to ourselves works on any device
+    let pid = unsafe { libc::getpid() };
+    let result = builder
+        .build()
+        .and_then(|mut analyzer| analyzer.attach_app(pid));
+
+    match result {
+        Ok(()) => Check::new("ebpf", Status::Ok, "loaded and attached"),
+        Err(e) => Check::new("ebpf", Status::Fail, e.to_string()),
+    }
+}
+
+// release and machine
+fn uname() -> Option<(String, String)> {
+    let mut name = unsafe { std::mem::zeroed::<libc::utsname>() };
+    if unsafe { libc::uname(&raw mut name) } != 0 {
+        return None;
+    }
+
+    let field = |field: &[libc::c_char]| {
+        unsafe { CStr::from_ptr(field.as_ptr()) }
+            .to_string_lossy()
+            .into_owned()
+    };
+    Some((field(&name.release), field(&name.machine)))
+}
@@ -0,0 +1,125 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{fmt, io, process::ExitCode};
+
+use frame_analyzer::AnalyzerError;
+
+/// Any other failure
+pub const FAILURE: u8 = 1;
+/// Invalid arguments or configuration, as clap exits with
+pub const USAGE: u8 = 2;
+/// A target process isn't running
+pub const NO_TARGET: u8 = 3;
+/// The ebpf program can't be loaded or attached, usually not root or an unsupported kernel
+pub const EBPF: u8 = 4;
+/// The recording is damaged or isn't a recording
+pub const BAD_RECORDING: u8 = 5;
+/// `doctor` found a failing check
+pub const CHECKS_FAILED: u8 = 6;
+
+/// The exit codes, for `--help`
+pub const HELP: &str = "\
+Exit codes:
+  0  success
+  1  any other failure
+  2  invalid arguments or configuration
+  3  a target process isn't running
+  4  the ebpf program can't be loaded or attached (not root, unsupported kernel)
+  5  the recording is damaged
+  6  doctor found a failing check";
+
+/// Failures of the tool itself, with their own exit code
+#[derive(Debug)]
+pub enum Failure {
+    /// A target can't be found
+    NoTarget(String),
+    /// How many doctor checks failed
+    ChecksFailed(usize),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoTarget(message) => f.write_str(message),
+            Self::ChecksFailed(1) => f.write_str("1 check failed"),
+            Self::ChecksFailed(count) => write!(f, "{count} checks failed"),
+        }
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/// The error with its causes, leaving out causes the error already tells
+pub fn message(error: &anyhow::Error) -> String {
+    let mut message = error.to_string();
+    for cause in error.chain().skip(1) {
+        let cause = cause.to_string();
+        if !message.contains(&cause) {
+            message.push_str(": ");
+            message.push_str(&cause);
+        }
+    }
+
+    message
+}
+
+/// The exit code of an error, from the first cause that tells
+pub fn code(error: &anyhow::Error) -> ExitCode {
+    let code = error
+        .chain()
+        .find_map(|cause| {
+            if let Some(failure) = cause.downcast_ref::<Failure>() {
+                return Some(match failure {
+                    Failure::NoTarget(_) => NO_TARGET,
+                    Failure::ChecksFailed(_) => CHECKS_FAILED,
+                });
+            }
+
+            cause
+                .downcast_ref::<AnalyzerError>()
+                .map(|error| match error {
+                    AnalyzerError::AppNotFound => NO_TARGET,
+                    AnalyzerError::EbpfError(_)
+                    | AnalyzerError::BpfProgramError(_)
+                    | AnalyzerError::BpfMapError(_)
+                    | AnalyzerError::UprobeAttachError(_)
+                    | AnalyzerError::AndroidPermissionDenied
+                    | AnalyzerError::MemlockError(_) => EBPF,
+                    AnalyzerError::ConfigError(_) => USAGE,
+                    AnalyzerError::RecordingError(_) => BAD_RECORDING,
+                    AnalyzerError::IOError(_) | AnalyzerError::FrameDataReadError(_) => FAILURE,
+                })
+        })
+        .unwrap_or(FAILURE);
+
+    ExitCode::from(code)
+}
+
+/// Whether the error is writing into a closed pipe
+pub fn broken_pipe(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        let io_error = cause.downcast_ref::<io::Error>().or_else(|| {
+            match cause.downcast_ref::<AnalyzerError>() {
+                Some(AnalyzerError::IOError(e)) => Some(e),
+                _ => None,
+            }
+        });
+        io_error.is_some_and(|e| e.kind() == io::ErrorKind::BrokenPipe)
+    })
+}
@@ -0,0 +1,117 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use clap::ValueEnum;
+use frame_analyzer::{
+    AnalyzerBuilder, ChromeTrace, Column, PerfettoTrace, Recording, TableFormat, TableSink,
+};
+
+use crate::jank_config;
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// The recording to convert
+    recording: PathBuf,
+    #[arg(short, long, value_enum)]
+    format: Format,
+    /// The file to write, the standard output if missing
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Comma separated columns of csv and jsonl, e.g. timestamp,pid,frametime,jank
+    #[arg(short, long, value_delimiter = ',')]
+    columns: Vec<Column>,
+    /// Count the frames missing this refresh rate as janky
+    #[arg(long)]
+    target_fps: Option<u32>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum Format {
+    /// A row per frame
+    Csv,
+    /// A JSON object per frame and line
+    Jsonl,
+    /// Chrome trace event JSON, for `chrome://tracing` and Perfetto
+    Chrome,
+    /// Native Perfetto protobuf trace
+    Perfetto,
+}
+
+/// Convert the recording with the analysis of `builder`'s configuration
+pub fn run(builder: &AnalyzerBuilder, args: Args) -> Result<()> {
+    let mut recording = Recording::open(&args.recording)?;
+    let config = builder.config();
+    let jank = jank_config(args.target_fps);
+
+    match args.format {
+        Format::Csv | Format::Jsonl => {
+            let format = if matches!(args.format, Format::Csv) {
+                TableFormat::Csv
+            } else {
+                TableFormat::JsonLines
+            };
+            let mut sink = match &args.output {
+                Some(path) => TableSink::create(format, path)?,
+                None => TableSink::stdout(format),
+            }
+            .jank_config(jank);
+            if !args.columns.is_empty() {
+                sink = sink.columns(args.columns);
+            }
+
+            sink.write_recording(&mut recording, config)?;
+            sink.finish()?;
+        }
+        Format::Chrome => {
+            let mut trace = match &args.output {
+                Some(path) => ChromeTrace::new(writer(path)?)?,
+                None => ChromeTrace::new(stdout())?,
+            }
+            .jank_config(jank);
+
+            trace.write_recording(&mut recording, config)?;
+            trace.finish()?.flush()?;
+        }
+        Format::Perfetto => {
+            let mut trace = match &args.output {
+                Some(path) => PerfettoTrace::new(writer(path)?),
+                None => PerfettoTrace::new(stdout()),
+            }
+            .jank_config(jank);
+
+            trace.write_recording(&mut recording, config)?;
+            trace.finish()?.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+fn writer(path: &Path) -> Result<Box<dyn Write>> {
+    Ok(Box::new(io::BufWriter::new(std::fs::File::create(path)?)))
+}
+
+fn stdout() -> Box<dyn Write> {
+    Box::new(io::BufWriter::new(io::stdout()))
+}
@@ -0,0 +1,121 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+#![warn(clippy::nursery, clippy::all, clippy::pedantic)]
+#![allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_precision_loss,
+    clippy::cast_sign_loss
+)]
+
+mod dashboard;
+mod doctor;
+mod exit;
+mod export;
+mod playback;
+mod record;
+mod replay;
+mod stats;
+mod stop;
+mod surfaces;
+mod target;
+mod watch;
+
+use std::{path::PathBuf, process::ExitCode};
+
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+use frame_analyzer::{AnalyzerBuilder, JankConfig};
+
+/// Track the frametime of Android apps
+#[derive(Parser, Debug)]
+#[command(name = "frame-analyzer", version, about, after_help = exit::HELP)]
+struct Cli {
+    /// Analyzer configuration, a JSON `AnalyzerConfig`
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the fps and janks of running apps every interval
+    Watch(watch::Args),
+    /// Full-screen view of the fps, janks and frametimes of running apps
+    Dashboard(dashboard::Args),
+    /// Record the frames of running apps into a file
+    Record(record::Args),
+    /// Print the frames of a recording
+    Replay(replay::Args),
+    /// Summarize the frames of a recording
+    Stats(stats::Args),
+    /// List the surfaces a running app draws to
+    Surfaces(surfaces::Args),
+    /// Convert a recording to CSV, JSON lines, Chrome trace or Perfetto
+    Export(export::Args),
+    /// Check whether the device can run the analyzer
+    Doctor(doctor::Args),
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    let result = match cli.command {
+        Command::Watch(args) => builder(cli.config).and_then(|builder| watch::run(builder, &args)),
+        Command::Dashboard(args) => {
+            builder(cli.config).and_then(|builder| dashboard::run(builder, &args))
+        }
+        Command::Record(args) => {
+            builder(cli.config).and_then(|builder| record::run(builder, &args))
+        }
+        Command::Replay(args) => builder(cli.config).and_then(|builder| replay::run(builder, args)),
+        Command::Stats(args) => builder(cli.config).and_then(|builder| stats::run(builder, &args)),
+        Command::Surfaces(args) => {
+            builder(cli.config).and_then(|builder| surfaces::run(builder, &args))
+        }
+        Command::Export(args) => {
+            builder(cli.config).and_then(|builder| export::run(&builder, args))
+        }
+        Command::Doctor(args) => {
+            builder(cli.config).and_then(|builder| doctor::run(builder, &args))
+        }
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        // the output was piped into something like `head`
+        Err(e) if exit::broken_pipe(&e) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", exit::message(&e));
+            exit::code(&e)
+        }
+    }
+}
+
+fn builder(config: Option<PathBuf>) -> Result<AnalyzerBuilder> {
+    Ok(match config {
+        Some(path) => AnalyzerBuilder::from_json_file(path)?,
+        None => AnalyzerBuilder::new(),
+    })
+}
+
+fn jank_config(target_fps: Option<u32>) -> JankConfig {
+    let config = JankConfig::default();
+    target_fps.map_or(config, |fps| config.target_fps(fps))
+}
@@ -0,0 +1,102 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{path::Path, time::Duration};
+
+use anyhow::Result;
+use frame_analyzer::{Analyzer, AnalyzerBuilder, FrameEvent, Pace, Pid, Recording, ReplaySource};
+
+use crate::exit::Failure;
+
+// how long a receive waits, so the caller can check whether to stop
+const POLL: Duration = Duration::from_millis(100);
+
+/// A recording played back through an analyzer
+pub struct Playback {
+    analyzer: Analyzer,
+    /// The recorded processes played back, with their cmdlines
+    pub pids: Vec<(Pid, String)>,
+}
+
+impl Playback {
+    /// Play back the processes of the recording in `pids`, all of them if it's empty
+    ///
+    /// Fails with `NoTarget` if one of `pids` or every process isn't in the recording
+    pub fn open(builder: AnalyzerBuilder, path: &Path, pace: Pace, pids: &[Pid]) -> Result<Self> {
+        // processes attached later in the recording are only listed where they were attached
+        let mut recording = Recording::open(path)?;
+        for frame in recording.by_ref() {
+            frame?;
+        }
+        let recorded: Vec<_> = recording
+            .pids()
+            .into_iter()
+            .filter(|(pid, _)| pids.is_empty() || pids.contains(pid))
+            .map(|(pid, cmdline)| (pid, cmdline.to_string()))
+            .collect();
+
+        if let Some(pid) = pids
+            .iter()
+            .find(|pid| !recorded.iter().any(|(recorded, _)| recorded == *pid))
+        {
+            return Err(Failure::NoTarget(format!("no process {pid} in the recording")).into());
+        }
+        if recorded.is_empty() {
+            return Err(Failure::NoTarget("no process in the recording".to_string()).into());
+        }
+
+        let source = ReplaySource::open(path, pace)?;
+        let mut analyzer = builder.source(source).build()?;
+        for (pid, _) in &recorded {
+            analyzer.attach_app(*pid)?;
+        }
+
+        Ok(Self {
+            analyzer,
+            pids: recorded,
+        })
+    }
+
+    /// The next frame, `None` if there's none yet or the recording is over
+    ///
+    /// Fails if the rest of the recording can't be read
+    pub fn recv(&mut self) -> Result<Option<FrameEvent>> {
+        let event = self.analyzer.recv_event_timeout(POLL);
+        if event.is_some() {
+            return Ok(event);
+        }
+
+        // the frames read before the recording ran out are handed out first
+        self.analyzer
+            .take_error()
+            .map_or(Ok(None), |error| Err(error.into()))
+    }
+
+    /// Whether every frame has been received
+    pub fn done(&self) -> bool {
+        self.analyzer.ended()
+    }
+
+    /// The cmdline of a recorded process
+    pub fn name(&self, pid: Pid) -> &str {
+        self.pids
+            .iter()
+            .find(|(recorded, _)| *recorded == pid)
+            .map_or("", |(_, name)| name.as_str())
+    }
+}
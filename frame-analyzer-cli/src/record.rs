@@ -0,0 +1,81 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{path::PathBuf, time::Duration};
+
+use anyhow::{Context, Result};
+use frame_analyzer::AnalyzerBuilder;
+
+use crate::{
+    stop::{Stop, parse_duration},
+    target,
+};
+
+// how long a receive waits, so Ctrl+C and exited processes are noticed
+const POLL: Duration = Duration::from_millis(100);
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Pids or package names
+    #[arg(required = true)]
+    targets: Vec<String>,
+    /// The recording to write
+    #[arg(short, long)]
+    output: PathBuf,
+    /// Stop after this long (e.g. 30s, 5m), Ctrl+C stops anyway
+    #[arg(short, long, value_parser = parse_duration)]
+    duration: Option<Duration>,
+}
+
+/// Record until stopped or every target exited
+pub fn run(builder: AnalyzerBuilder, args: &Args) -> Result<()> {
+    let mut targets = target::resolve(&args.targets)?;
+    let mut analyzer = builder.build()?;
+    for target in &targets {
+        analyzer
+            .attach_app(target.pid)
+            .with_context(|| format!("failed to attach to {} ({})", target.pid, target.name))?;
+    }
+
+    analyzer
+        .record(&args.output)
+        .with_context(|| format!("failed to create {}", args.output.display()))?;
+    eprintln!(
+        "recording into {}, press Ctrl+C to stop",
+        args.output.display()
+    );
+
+    let stop = Stop::new(args.duration)?;
+    let mut frames = 0_u64;
+    while !stop.stopped() {
+        if analyzer.recv_event_timeout(POLL).is_some() {
+            frames += 1;
+            continue;
+        }
+
+        targets.retain(|target| target::alive(target.pid));
+        if targets.is_empty() {
+            eprintln!("every target exited");
+            break;
+        }
+    }
+
+    analyzer.stop_recording()?;
+    eprintln!("recorded {frames} frames");
+    Ok(())
+}
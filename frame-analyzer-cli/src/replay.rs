@@ -0,0 +1,80 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use frame_analyzer::{AnalyzerBuilder, Column, Pace, Pid, TableFormat, TableSink};
+
+use crate::{jank_config, playback::Playback, stop::Stop};
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// The recording to play
+    recording: PathBuf,
+    /// Print the frames as they were recorded rather than at once
+    #[arg(long)]
+    realtime: bool,
+    /// Only the frames of these recorded processes
+    #[arg(short, long)]
+    pid: Vec<Pid>,
+    #[arg(short, long, value_enum, default_value_t = Format::Csv)]
+    format: Format,
+    /// Comma separated columns, e.g. timestamp,pid,frametime,jank
+    #[arg(short, long, value_delimiter = ',')]
+    columns: Vec<Column>,
+    /// Count the frames missing this refresh rate as janky
+    #[arg(long)]
+    target_fps: Option<u32>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum Format {
+    Csv,
+    Jsonl,
+}
+
+/// Print a row per frame of the recording
+pub fn run(builder: AnalyzerBuilder, args: Args) -> Result<()> {
+    let pace = if args.realtime {
+        Pace::RealTime
+    } else {
+        Pace::Fast
+    };
+    let mut playback = Playback::open(builder, &args.recording, pace, &args.pid)?;
+
+    let format = match args.format {
+        Format::Csv => TableFormat::Csv,
+        Format::Jsonl => TableFormat::JsonLines,
+    };
+    let mut sink = TableSink::stdout(format).jank_config(jank_config(args.target_fps));
+    if !args.columns.is_empty() {
+        sink = sink.columns(args.columns);
+    }
+
+    let stop = Stop::new(None)?;
+    while !playback.done() && !stop.stopped() {
+        if let Some(event) = playback.recv()? {
+            sink.push(&event)?;
+        }
+    }
+
+    sink.finish()?;
+    Ok(())
+}
@@ -0,0 +1,151 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
+
+use anyhow::Result;
+use frame_analyzer::{AnalyzerBuilder, FrameStats, JankDetector, Pace, Pid, Recording, Window};
+use serde_json::json;
+
+use crate::{jank_config, playback::Playback};
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// The recording to summarize
+    recording: PathBuf,
+    /// Print JSON instead of text
+    #[arg(long)]
+    json: bool,
+    /// Also count the frames missing this refresh rate as janky
+    #[arg(long)]
+    target_fps: Option<u32>,
+}
+
+struct Process {
+    stats: FrameStats,
+    jank: JankDetector,
+    first_ns: u64,
+    last_ns: u64,
+}
+
+/// Summarize every recorded process
+pub fn run(builder: AnalyzerBuilder, args: &Args) -> Result<()> {
+    let metadata = Recording::open(&args.recording)?.metadata().to_vec();
+    let mut playback = Playback::open(builder, &args.recording, Pace::Fast, &[])?;
+    let mut processes: BTreeMap<Pid, Process> = BTreeMap::new();
+
+    while !playback.done() {
+        let Some(event) = playback.recv()? else {
+            continue;
+        };
+
+        let process = processes.entry(event.pid).or_insert_with(|| Process {
+            stats: FrameStats::new(Window::All),
+            jank: JankDetector::new(jank_config(args.target_fps)),
+            first_ns: event.timestamp_ns,
+            last_ns: event.timestamp_ns,
+        });
+        process.stats.push_event(&event);
+        process.jank.push_event(&event);
+        process.last_ns = event.timestamp_ns;
+    }
+
+    let summaries: Vec<_> = processes
+        .iter()
+        .filter_map(|(pid, process)| {
+            let duration = Duration::from_nanos(process.last_ns - process.first_ns);
+            Some((
+                *pid,
+                duration,
+                process.stats.summary()?,
+                *process.jank.totals(),
+            ))
+        })
+        .collect();
+
+    if args.json {
+        let processes: Vec<_> = summaries
+            .iter()
+            .map(|(pid, duration, summary, janks)| {
+                json!({
+                    "pid": pid,
+                    "name": playback.name(*pid),
+                    "duration_s": duration.as_secs_f64(),
+                    "summary": summary,
+                    "janks": janks,
+                    "stutter": janks.stutter(),
+                    "janks_per_hour": janks.janks_per_hour(),
+                    "janky_ratio": janks.janky_ratio(),
+                })
+            })
+            .collect();
+        // keys like `probe` repeat, so pairs rather than an object
+        println!(
+            "{}",
+            json!({ "metadata": metadata, "processes": processes })
+        );
+        return Ok(());
+    }
+
+    for (key, value) in &metadata {
+        if key != "pid" {
+            println!("{key}: {value}");
+        }
+    }
+    for (pid, duration, summary, janks) in &summaries {
+        println!();
+        println!("{pid} {}", playback.name(*pid));
+        println!(
+            "  frames    {} over {:.1}s",
+            summary.frames,
+            duration.as_secs_f64()
+        );
+        println!(
+            "  fps       {:.1} average, {:.1} 1% low, {:.1} 0.1% low",
+            summary.average_fps, summary.low_1, summary.low_0_1
+        );
+        println!(
+            "  frametime {} p50, {} p90, {} p99, {} max, {} std dev",
+            ms(summary.p50),
+            ms(summary.p90),
+            ms(summary.p99),
+            ms(summary.max),
+            ms(summary.std_dev)
+        );
+        println!(
+            "  janks     {} ({} big), {:.2}% stutter, {:.1} per hour",
+            janks.janks,
+            janks.big_janks,
+            janks.stutter() * 100.0,
+            janks.janks_per_hour()
+        );
+        if args.target_fps.is_some() {
+            println!(
+                "  janky     {} frames, {:.2}%",
+                janks.janky_frames,
+                janks.janky_ratio() * 100.0
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn ms(duration: Duration) -> String {
+    format!("{:.2}ms", duration.as_secs_f64() * 1000.0)
+}
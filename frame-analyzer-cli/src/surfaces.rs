@@ -0,0 +1,93 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use frame_analyzer::{AnalyzerBuilder, FrameStats, Pid, SurfaceSelection, Window};
+
+use crate::{
+    stop::{Stop, parse_duration},
+    target,
+};
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Pid or package name
+    target: String,
+    /// How long to watch for frames
+    #[arg(short, long, default_value = "3s", value_parser = parse_duration)]
+    duration: Duration,
+}
+
+/// List the surfaces that queued frames while watching, busiest first
+pub fn run(builder: AnalyzerBuilder, args: &Args) -> Result<()> {
+    let targets = target::resolve(std::slice::from_ref(&args.target))?;
+    let mut analyzer = builder.surface_selection(SurfaceSelection::All).build()?;
+    for target in &targets {
+        analyzer
+            .attach_app(target.pid)
+            .with_context(|| format!("failed to attach to {} ({})", target.pid, target.name))?;
+    }
+
+    let stop = Stop::new(Some(args.duration))?;
+    let end = Instant::now() + args.duration;
+    let mut surfaces: BTreeMap<(Pid, usize), FrameStats> = BTreeMap::new();
+    while !stop.stopped() {
+        let timeout = end.saturating_duration_since(Instant::now());
+        if let Some(event) = analyzer.recv_event_timeout(timeout) {
+            surfaces
+                .entry((event.pid, event.surface))
+                .or_insert_with(|| FrameStats::new(Window::All))
+                .push_event(&event);
+        }
+    }
+
+    if surfaces.is_empty() {
+        eprintln!(
+            "no surface queued a frame in {:.1}s",
+            args.duration.as_secs_f64()
+        );
+        return Ok(());
+    }
+
+    let mut surfaces: Vec<_> = surfaces.into_iter().collect();
+    surfaces.sort_by_key(|((pid, _), stats)| (*pid, std::cmp::Reverse(stats.len())));
+
+    println!(
+        "{:>7} {:>18} {:>7} {:>6}  NAME",
+        "PID", "SURFACE", "FRAMES", "FPS"
+    );
+    for ((pid, surface), stats) in &surfaces {
+        let name = targets
+            .iter()
+            .find(|target| target.pid == *pid)
+            .map_or("", |target| target.name.as_str());
+        println!(
+            "{pid:>7} {:>18} {:>7} {:>6.1}  {name}",
+            format!("{surface:#x}"),
+            stats.len(),
+            stats.average_fps().unwrap_or_default()
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,93 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{fs, path::Path};
+
+use anyhow::Result;
+use frame_analyzer::Pid;
+
+use crate::exit::Failure;
+
+/// A running process to analyze
+#[derive(Debug, Clone)]
+pub struct Target {
+    pub pid: Pid,
+    /// Its cmdline, the package name for an app
+    pub name: String,
+}
+
+/// Resolve pids and package names, a package stands for all of its processes (`<package>` and `<package>:<name>`)
+pub fn resolve(targets: &[String]) -> Result<Vec<Target>> {
+    let mut resolved: Vec<Target> = Vec::new();
+
+    for target in targets {
+        let found = if let Ok(pid) = target.parse::<Pid>() {
+            let name = name(pid).ok_or_else(|| Failure::NoTarget(format!("no process {pid}")))?;
+            vec![Target { pid, name }]
+        } else {
+            processes()
+                .filter(|process| {
+                    process.name == *target
+                        || process
+                            .name
+                            .strip_prefix(target.as_str())
+                            .is_some_and(|rest| rest.starts_with(':'))
+                })
+                .collect()
+        };
+
+        if found.is_empty() {
+            return Err(Failure::NoTarget(format!("{target} isn't running")).into());
+        }
+        resolved.extend(found);
+    }
+
+    resolved.sort_by_key(|target| target.pid);
+    resolved.dedup_by_key(|target| target.pid);
+    Ok(resolved)
+}
+
+/// Whether the process is still running
+pub fn alive(pid: Pid) -> bool {
+    Path::new(&format!("/proc/{pid}")).exists()
+}
+
+/// The cmdline of a process, or its comm if the cmdline is empty (kernel threads)
+pub fn name(pid: Pid) -> Option<String> {
+    let cmdline = fs::read(format!("/proc/{pid}/cmdline")).ok()?;
+    let name = cmdline.split(|byte| *byte == 0).next().unwrap_or_default();
+    if !name.is_empty() {
+        return Some(String::from_utf8_lossy(name).into_owned());
+    }
+
+    let comm = fs::read_to_string(format!("/proc/{pid}/comm")).ok()?;
+    Some(comm.trim_end().to_string())
+}
+
+fn processes() -> impl Iterator<Item = Target> {
+    fs::read_dir("/proc")
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let pid = entry.ok()?.file_name().to_str()?.parse().ok()?;
+            Some(Target {
+                pid,
+                name: name(pid)?,
+            })
+        })
+}
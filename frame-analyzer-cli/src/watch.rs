@@ -0,0 +1,162 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use frame_analyzer::{
+    AnalyzerBuilder, FrameStats, JankConfig, JankDetector, JankTotals, Pid, Window,
+};
+
+use crate::{
+    jank_config,
+    stop::{Stop, parse_duration},
+    target::{self, Target},
+};
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Pids or package names
+    #[arg(required = true)]
+    targets: Vec<String>,
+    /// How often a row is printed for every target
+    #[arg(short, long, default_value = "1s", value_parser = parse_duration)]
+    interval: Duration,
+    /// Stop after this long (e.g. 30s, 5m), Ctrl+C stops anyway
+    #[arg(short, long, value_parser = parse_duration)]
+    duration: Option<Duration>,
+    /// Also count the frames missing this refresh rate as janky
+    #[arg(long)]
+    target_fps: Option<u32>,
+}
+
+/// One target, the stats are of the current interval
+pub struct Row {
+    pub target: Target,
+    pub stats: FrameStats,
+    pub jank: JankDetector,
+    // the jank totals at the end of the last interval
+    reported: JankTotals,
+}
+
+impl Row {
+    pub fn new(target: Target, jank: JankConfig) -> Self {
+        Self {
+            target,
+            stats: FrameStats::new(Window::All),
+            jank: JankDetector::new(jank),
+            reported: JankTotals::default(),
+        }
+    }
+
+    /// End the interval, returning its janks
+    pub fn next_interval(&mut self) -> JankTotals {
+        let totals = *self.jank.totals();
+        let interval = JankTotals {
+            frames: totals.frames - self.reported.frames,
+            total_time: totals.total_time.saturating_sub(self.reported.total_time),
+            janks: totals.janks - self.reported.janks,
+            big_janks: totals.big_janks - self.reported.big_janks,
+            jank_time: totals.jank_time.saturating_sub(self.reported.jank_time),
+            janky_frames: totals.janky_frames - self.reported.janky_frames,
+        };
+
+        self.reported = totals;
+        self.stats.clear();
+        interval
+    }
+}
+
+/// Print a row per target and interval until stopped or every target exited
+pub fn run(builder: AnalyzerBuilder, args: &Args) -> Result<()> {
+    let targets = target::resolve(&args.targets)?;
+    let mut analyzer = builder.build()?;
+    let mut rows = BTreeMap::new();
+    for target in targets {
+        analyzer
+            .attach_app(target.pid)
+            .with_context(|| format!("failed to attach to {} ({})", target.pid, target.name))?;
+        rows.insert(target.pid, Row::new(target, jank_config(args.target_fps)));
+    }
+
+    let stop = Stop::new(args.duration)?;
+    let start = Instant::now();
+    let mut next = start + args.interval;
+    println!(
+        "{:>8} {:>7} {:>6} {:>6} {:>7} {:>5} {:>5} {:>7}  NAME",
+        "TIME", "PID", "FPS", "1%LOW", "P99_MS", "JANK", "BIG", "STUTTER"
+    );
+
+    while !stop.stopped() {
+        let timeout = next.saturating_duration_since(Instant::now());
+        let event = analyzer.recv_event_timeout(timeout);
+        if let Some((event, row)) = event.and_then(|event| Some((event, rows.get_mut(&event.pid)?)))
+        {
+            row.stats.push_event(&event);
+            row.jank.push_event(&event);
+        }
+
+        if Instant::now() < next {
+            continue;
+        }
+        next += args.interval;
+
+        let time = start.elapsed().as_secs_f64();
+        for row in rows.values_mut() {
+            let fps = row.stats.average_fps().unwrap_or_default();
+            let low = optional(row.stats.low(1.0), |low| format!("{low:.1}"));
+            let p99 = optional(row.stats.percentile(99.0), |p99| {
+                format!("{:.1}", p99.as_secs_f64() * 1000.0)
+            });
+            let janks = row.next_interval();
+
+            println!(
+                "{time:>7.1}s {:>7} {fps:>6.1} {low:>6} {p99:>7} {:>5} {:>5} {:>6.1}%  {}",
+                row.target.pid,
+                janks.janks,
+                janks.big_janks,
+                janks.stutter() * 100.0,
+                row.target.name
+            );
+        }
+
+        let exited: Vec<Pid> = rows
+            .keys()
+            .copied()
+            .filter(|pid| !target::alive(*pid))
+            .collect();
+        for pid in exited {
+            let _ = analyzer.detach_app(pid);
+            if let Some(row) = rows.remove(&pid) {
+                eprintln!("{pid} ({}) exited", row.target.name);
+            }
+        }
+        if rows.is_empty() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+fn optional<T>(value: Option<T>, f: impl FnOnce(T) -> String) -> String {
+    value.map_or_else(|| "-".to_string(), f)
+}
@@ -18,6 +18,11 @@
  */
 #![no_std]
 
+/// `CLOCK_SOURCE` of the ebpf program: timestamp frames with `bpf_ktime_get_ns` (`CLOCK_MONOTONIC`)
+pub const CLOCK_MONOTONIC: u32 = 0;
+/// `CLOCK_SOURCE` of the ebpf program: timestamp frames with `bpf_ktime_get_boot_ns` (`CLOCK_BOOTTIME`)
+pub const CLOCK_BOOTTIME: u32 = 1;
+
 #[repr(C)]
 pub struct FrameSignal {
     pub ktime_ns: u64,
@@ -22,19 +22,23 @@
 #![allow(clippy::unused_unit)] // 抑制aya-ebpf宏的未使用单元警告
 
 use aya_ebpf::{
-    helpers::bpf_ktime_get_ns,
+    helpers::{bpf_ktime_get_boot_ns, bpf_ktime_get_ns},
     macros::{map, uprobe},
     maps::RingBuf,
     programs::ProbeContext,
     BpfContext,
 };
 
-use frame_analyzer_ebpf_common::FrameSignal;
+use frame_analyzer_ebpf_common::{CLOCK_BOOTTIME, FrameSignal};
 
 // 适配aya-ebpf 0.1.1：RingBuf使用默认构造，容量通过map配置（该版本with_byte_size未实现）
 #[map]
 static RING_BUF: RingBuf = RingBuf::new(0); // 0为占位，实际容量由用户态加载时指定
 
+// 时钟源，由用户态加载时通过set_global写入（见frame_analyzer_ebpf_common::CLOCK_*）
+#[unsafe(no_mangle)]
+static CLOCK_SOURCE: u32 = 0;
+
 #[uprobe]
 pub fn frame_analyzer_ebpf(ctx: ProbeContext) -> u32 {
     match try_frame_analyzer_ebpf(ctx) {
@@ -50,8 +54,12 @@ fn try_frame_analyzer_ebpf(mut ctx: ProbeContext) -> Result<u32, u32> {
     // 修复：RingBuf.reserve在0.1.1中返回Result，而非Option
     let mut entry = RING_BUF.reserve::<FrameSignal>().map_err(|_| 2)?; // 错误码2：缓冲区满
 
-    // 安全调用bpf_ktime_get_ns（eBPF内核辅助函数，无未定义行为）
-    let ktime_ns = bpf_ktime_get_ns();
+    // 按用户态选择的时钟源取时间戳，read_volatile防止编译器把全局变量常量折叠
+    let ktime_ns = if unsafe { core::ptr::read_volatile(&raw const CLOCK_SOURCE) } == CLOCK_BOOTTIME {
+        unsafe { bpf_ktime_get_boot_ns() }
+    } else {
+        unsafe { bpf_ktime_get_ns() }
+    };
 
     // 写入帧信号数据并提交
     entry.write(FrameSignal::new(ktime_ns, arg0));
@@ -0,0 +1,143 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Per-frame cost of `Analyzer::recv`
+//!
+//! Loading the ebpf program needs root, so a source waiting on eventfds stands in for the ring fds of [`frame_analyzer::EbpfSource`]
+
+use std::{
+    hint::black_box,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    time::Duration,
+};
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use frame_analyzer::{AnalyzerBuilder, AnalyzerError, FrameSource, Pid, RawFrame};
+use mio::{Events, Interest, Poll, Token, unix::SourceFd};
+
+const APPS: [usize; 3] = [1, 4, 16];
+// 120 fps
+const FRAMETIME_NS: u64 = 8_333_333;
+
+/// Hands out a frame of the next app on every read, waiting on its ring like `EbpfSource`
+struct RingSource {
+    poll: Poll,
+    events: Events,
+    rings: Vec<OwnedFd>,
+    next: usize,
+    now_ns: u64,
+}
+
+impl RingSource {
+    fn new(apps: usize) -> Self {
+        let rings = (0..apps)
+            .map(|_| {
+                let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+                assert!(fd >= 0, "eventfd failed");
+                unsafe { OwnedFd::from_raw_fd(fd) }
+            })
+            .collect();
+
+        let source = Self {
+            poll: Poll::new().unwrap(),
+            events: Events::with_capacity(1024),
+            rings,
+            next: 0,
+            now_ns: 0,
+        };
+        for (token, ring) in source.rings.iter().enumerate() {
+            source
+                .poll
+                .registry()
+                .register(
+                    &mut SourceFd(&ring.as_raw_fd()),
+                    Token(token),
+                    Interest::READABLE,
+                )
+                .unwrap();
+        }
+
+        source
+    }
+}
+
+impl FrameSource for RingSource {
+    fn attach(&mut self, _pid: Pid) -> Result<(), AnalyzerError> {
+        Ok(())
+    }
+
+    fn detach(&mut self, _pid: Pid) -> Result<(), AnalyzerError> {
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        frames: &mut Vec<RawFrame>,
+        timeout: Option<Duration>,
+    ) -> Result<(), AnalyzerError> {
+        // the app queues a buffer
+        let app = self.next % self.rings.len();
+        self.next += 1;
+        let value = 1u64;
+        unsafe { libc::write(self.rings[app].as_raw_fd(), (&raw const value).cast(), 8) };
+
+        self.poll.poll(&mut self.events, timeout)?;
+        for event in &self.events {
+            let Token(ring) = event.token();
+            let mut value = 0u64;
+            unsafe { libc::read(self.rings[ring].as_raw_fd(), (&raw mut value).cast(), 8) };
+
+            self.now_ns += FRAMETIME_NS;
+            frames.push(RawFrame {
+                pid: ring as Pid,
+                ktime_ns: self.now_ns,
+                buffer: 0x1,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn now_ns(&self) -> u64 {
+        self.now_ns
+    }
+}
+
+fn bench_recv(c: &mut Criterion) {
+    let mut group = c.benchmark_group("recv");
+
+    for apps in APPS {
+        group.bench_with_input(BenchmarkId::from_parameter(apps), &apps, |b, &apps| {
+            let mut analyzer = AnalyzerBuilder::new()
+                .source(RingSource::new(apps))
+                .build()
+                .unwrap();
+            for pid in 0..apps {
+                analyzer.attach_app(pid as Pid).unwrap();
+            }
+
+            b.iter(|| black_box(analyzer.recv()));
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_recv);
+criterion_main!(benches);
@@ -18,65 +18,234 @@
  */
 use std::{
     collections::{HashMap, VecDeque},
-    ptr,
     time::Duration,
 };
 
-use frame_analyzer_ebpf_common::FrameSignal;
+use crate::{
+    Pid,
+    config::{AnalyzerConfig, SurfaceSelection},
+    event::{FrameEvent, RawFrame},
+};
 
-use crate::uprobe::UprobeHandler;
+struct Surface {
+    since: u64,
+    timestamp: u64,
+    frametimes: VecDeque<Duration>,
+    seq: u64,
+}
 
 pub struct AnalyzeTarget {
-    pub uprobe: UprobeHandler,
-    buffers: HashMap<usize, (u64, VecDeque<Duration>)>,
+    pid: Pid,
+    history: usize,
+    idle_threshold: Duration,
+    selection: SurfaceSelection,
+    surfaces: HashMap<usize, Surface>,
 }
 
 impl AnalyzeTarget {
-    pub fn new(uprobe: UprobeHandler) -> Self {
+    pub fn new(pid: Pid, config: &AnalyzerConfig) -> Self {
         Self {
-            uprobe,
-            buffers: HashMap::new(),
+            pid,
+            history: config.history,
+            idle_threshold: config.idle_threshold,
+            selection: config.surface_selection,
+            surfaces: HashMap::new(),
         }
     }
 
-    pub fn update(&mut self) -> Option<Duration> {
-        let mut ring = self.uprobe.ring().unwrap();
-        let item = ring.next()?;
-        let event = unsafe { trans(&item) };
-        if let Some((timestamp, buffer)) = self.buffers.get_mut(&event.buffer) {
-            let frametime = event.ktime_ns.saturating_sub(*timestamp);
-            *timestamp = event.ktime_ns;
+    /// Analyze a raw frame, returns the frame if it belongs to the surface that is being tracked
+    pub fn process(&mut self, event: &RawFrame) -> Option<FrameEvent> {
+        let Some(surface) = self.surfaces.get_mut(&event.buffer) else {
+            // the first signal of a surface only gives the start of its first frame
+            self.surfaces.insert(
+                event.buffer,
+                Surface {
+                    since: event.ktime_ns,
+                    timestamp: event.ktime_ns,
+                    frametimes: VecDeque::with_capacity(self.history),
+                    seq: 0,
+                },
+            );
+            return None;
+        };
 
-            if buffer.len() >= 144 {
-                buffer.pop_back();
-            }
+        let frametime = Duration::from_nanos(event.ktime_ns.saturating_sub(surface.timestamp));
+        let seq = surface.seq;
+        surface.timestamp = event.ktime_ns;
+        surface.seq += 1;
 
-            buffer.push_front(Duration::from_nanos(frametime));
-        } else {
-            self.buffers
-                .insert(event.buffer, (event.ktime_ns, VecDeque::with_capacity(144)));
+        if surface.frametimes.len() >= self.history {
+            surface.frametimes.pop_back();
         }
+        surface.frametimes.push_front(frametime);
+
+        (self
+            .selected()
+            .is_none_or(|selected| selected == event.buffer))
+        .then(|| FrameEvent {
+            pid: self.pid,
+            timestamp_ns: event.ktime_ns,
+            frametime,
+            surface: event.buffer,
+            seq,
+            after_idle: frametime >= self.idle_threshold,
+        })
+    }
 
-        let max_len = self
-            .buffers
-            .values()
-            .map(|(_, buffer)| buffer.len())
-            .max()
-            .unwrap_or_default();
-        if self.buffers.get(&event.buffer)
-            == self
-                .buffers
-                .values()
-                .filter(|(_, buffer)| buffer.len() == max_len)
-                .min_by_key(|(_, buffer)| buffer.iter().copied().sum::<Duration>())
-        {
-            self.buffers.get(&event.buffer)?.1.front().copied()
-        } else {
-            None
+    /// The surface whose frames are reported, `None` for all of them
+    fn selected(&self) -> Option<usize> {
+        match self.selection {
+            SurfaceSelection::LongestHistory => {
+                let max_len = self
+                    .surfaces
+                    .values()
+                    .map(|surface| surface.frametimes.len())
+                    .max()
+                    .unwrap_or_default();
+
+                self.surfaces
+                    .iter()
+                    .filter(|(_, surface)| surface.frametimes.len() == max_len)
+                    .min_by_key(|(_, surface)| surface.frametimes.iter().copied().sum::<Duration>())
+                    .map(|(buffer, _)| *buffer)
+            }
+            SurfaceSelection::Newest => self
+                .surfaces
+                .iter()
+                .max_by_key(|(_, surface)| surface.since)
+                .map(|(buffer, _)| *buffer),
+            SurfaceSelection::All => None,
         }
     }
 }
 
-const unsafe fn trans(buf: &[u8]) -> FrameSignal {
-    unsafe { ptr::read_unaligned(buf.as_ptr().cast::<FrameSignal>()) }
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{AnalyzerBuilder, FrameEvent, Script, SurfaceSelection, SyntheticSource};
+
+    const PID: i32 = 1000;
+    const PERIOD: Duration = Duration::from_micros(16_667);
+
+    fn analyze(
+        builder: AnalyzerBuilder,
+        scripts: impl IntoIterator<Item = Script>,
+    ) -> Vec<FrameEvent> {
+        let source = scripts
+            .into_iter()
+            .fold(SyntheticSource::new(), SyntheticSource::script);
+        let mut analyzer = builder.source(source).build().unwrap();
+        analyzer.attach_app(PID).unwrap();
+
+        let mut events = Vec::new();
+        while !analyzer.ended() {
+            events.extend(analyzer.recv_event());
+        }
+        events
+    }
+
+    fn surfaces(events: &[FrameEvent]) -> Vec<(usize, usize)> {
+        let mut surfaces: Vec<(usize, usize)> = Vec::new();
+        for event in events {
+            match surfaces.last_mut() {
+                Some((surface, count)) if *surface == event.surface => *count += 1,
+                _ => surfaces.push((event.surface, 1)),
+            }
+        }
+        surfaces
+    }
+
+    #[test]
+    fn longest_history_ignores_short_surfaces() {
+        let game = Script::new(PID, 0x1).frames(300, PERIOD);
+        let overlay = Script::new(PID, 0x2).frames(10, Duration::from_millis(500));
+
+        let events = analyze(AnalyzerBuilder::new(), [game, overlay]);
+        assert_eq!(surfaces(&events), [(0x1, 300)]);
+    }
+
+    #[test]
+    fn longest_history_switches_once_the_history_matches() {
+        // the new surface is a bit faster, so it wins once the histories are as long
+        let faster = Duration::from_millis(16);
+        let script = |frames| {
+            Script::new(PID, 0x1)
+                .frames(frames, PERIOD)
+                .surface(0x2)
+                .frames(100, faster)
+        };
+
+        // before the history fills, the new surface needs as many frames as the old one drew
+        let events = analyze(AnalyzerBuilder::new().history(60), [script(20)]);
+        assert_eq!(surfaces(&events), [(0x1, 20), (0x2, 100 - 19)]);
+        assert_eq!(events[20].seq, 19);
+
+        // after, it needs a full history however long the old one drew
+        let events = analyze(AnalyzerBuilder::new().history(60), [script(500)]);
+        assert_eq!(surfaces(&events), [(0x1, 500), (0x2, 100 - 59)]);
+    }
+
+    #[test]
+    fn newest_switches_right_away() {
+        let script = Script::new(PID, 0x1)
+            .frames(60, PERIOD)
+            .surface(0x2)
+            .frames(60, PERIOD)
+            // the old surface drawing again isn't reported
+            .surface(0x1)
+            .frames(60, PERIOD);
+
+        let builder = AnalyzerBuilder::new().surface_selection(SurfaceSelection::Newest);
+        let events = analyze(builder, [script]);
+        assert_eq!(surfaces(&events), [(0x1, 60), (0x2, 60)]);
+    }
+
+    #[test]
+    fn all_reports_every_surface() {
+        let game = Script::new(PID, 0x1).frames(300, PERIOD);
+        let overlay = Script::new(PID, 0x2).frames(10, Duration::from_millis(500));
+
+        let builder = AnalyzerBuilder::new().surface_selection(SurfaceSelection::All);
+        let events = analyze(builder, [game, overlay]);
+        assert_eq!(events.len(), 310);
+        assert_eq!(
+            events.iter().filter(|event| event.surface == 0x2).count(),
+            10
+        );
+    }
+
+    #[test]
+    fn dropped_frames_span_the_gap() {
+        let script = Script::new(PID, 0x1)
+            .frames(30, PERIOD)
+            .drop(2)
+            .frames(30, PERIOD);
+
+        let events = analyze(AnalyzerBuilder::new(), [script]);
+        assert_eq!(events.len(), 58);
+        assert_eq!(events[30].frametime, PERIOD * 3);
+        assert!(!events[30].after_idle);
+        assert!(
+            events
+                .iter()
+                .enumerate()
+                .all(|(i, event)| event.seq == i as u64)
+        );
+    }
+
+    #[test]
+    fn idle_gaps_mark_the_next_frame() {
+        let script = Script::new(PID, 0x1)
+            .frames(30, PERIOD)
+            .idle(Duration::from_secs(2))
+            .frames(30, PERIOD);
+
+        let builder = AnalyzerBuilder::new().idle_threshold(Duration::from_secs(1));
+        let events = analyze(builder, [script]);
+        assert_eq!(events.len(), 60);
+        assert_eq!(events[30].frametime, Duration::from_secs(2));
+        assert_eq!(events.iter().filter(|event| event.after_idle).count(), 1);
+        assert!(events[30].after_idle);
+    }
 }
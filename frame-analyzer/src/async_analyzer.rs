@@ -0,0 +1,364 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{
+    os::unix::io::RawFd,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{Stream, task::AtomicWaker};
+use tokio::{
+    io::{Interest, unix::AsyncFd},
+    time::{Interval, MissedTickBehavior},
+};
+
+use crate::{Analyzer, FrameEvent, Pid, error::Result};
+
+// How often the analyzer is read without its source turning readable, to notice idle surfaces and foreground changes,
+// flush recordings and read sources without a file descriptor
+const TICK: Duration = Duration::from_millis(50);
+
+struct Shared {
+    analyzer: Mutex<Analyzer>,
+    waker: AtomicWaker,
+}
+
+impl Shared {
+    fn analyzer(&self) -> MutexGuard<'_, Analyzer> {
+        self.analyzer.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// An [`Analyzer`] for tokio
+///
+/// A [`Stream`] of the [`FrameEvent`]s of the analyzer, waiting on the rings of the attached apps through the tokio reactor instead of a blocked thread.
+/// The stream ends once the source can't deliver any more frames, see [`Analyzer::ended`].
+/// Available with the `tokio` feature
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use frame_analyzer::{AnalyzerBuilder, AsyncAnalyzer, Script, SyntheticSource};
+/// use futures::StreamExt;
+/// #
+/// # #[tokio::main]
+/// # async fn main() {
+/// # try_main().await.unwrap();
+/// # }
+/// #
+/// # async fn try_main() -> anyhow::Result<()> {
+/// let source = SyntheticSource::new().script(Script::new(1000, 0x1).frames(60, Duration::from_millis(16)));
+/// let mut analyzer = AsyncAnalyzer::new(AnalyzerBuilder::new().source(source).build()?)?;
+/// analyzer.attach_app(1000)?;
+///
+/// let mut frames = 0;
+/// while let Some(event) = analyzer.next().await {
+/// println!("process: {}, frametime: {:?}", event.pid, event.frametime);
+/// frames += 1;
+/// }
+/// assert_eq!(frames, 60);
+/// # Ok(())
+/// # }
+/// ```
+pub struct AsyncAnalyzer {
+    // registered with the reactor, must be dropped before the source closes the file descriptor
+    ready: Option<AsyncFd<RawFd>>,
+    tick: Interval,
+    shared: Arc<Shared>,
+}
+
+/// A handle to an [`AsyncAnalyzer`], to attach and detach apps while its stream is being polled elsewhere
+///
+/// # Examples
+///
+/// ```
+/// use frame_analyzer::{Analyzer, AsyncAnalyzer};
+/// use futures::StreamExt;
+/// #
+/// # #[tokio::main]
+/// # async fn main() {
+/// # let _ = try_main().await;
+/// # }
+/// #
+/// # async fn try_main() -> anyhow::Result<()> {
+/// # let app_pid = 1;
+/// let mut analyzer = AsyncAnalyzer::new(Analyzer::new()?)?;
+/// let handle = analyzer.handle();
+///
+/// tokio::spawn(async move {
+/// while let Some(event) = analyzer.next().await {
+/// println!("process: {}, frametime: {:?}", event.pid, event.frametime);
+/// }
+/// });
+///
+/// handle.attach_app(app_pid)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct AsyncAnalyzerHandle {
+    shared: Arc<Shared>,
+}
+
+impl AsyncAnalyzer {
+    /// Drive `analyzer` with the tokio reactor
+    ///
+    /// # Errors
+    ///
+    /// `IOError` if the source can't be registered with the reactor
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a tokio runtime
+    pub fn new(analyzer: Analyzer) -> Result<Self> {
+        let ready = analyzer
+            .source
+            .ready_fd()
+            .map(|fd| AsyncFd::with_interest(fd, Interest::READABLE))
+            .transpose()?;
+        let mut tick = tokio::time::interval(TICK);
+        tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        Ok(Self {
+            ready,
+            tick,
+            shared: Arc::new(Shared {
+                analyzer: Mutex::new(analyzer),
+                waker: AtomicWaker::new(),
+            }),
+        })
+    }
+
+    /// A handle sharing the analyzer of this stream
+    #[must_use]
+    pub fn handle(&self) -> AsyncAnalyzerHandle {
+        AsyncAnalyzerHandle {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// See [`Analyzer::attach_app`]
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Analyzer::attach_app`]
+    pub fn attach_app(&self, pid: Pid) -> Result<()> {
+        attach_app(&self.shared, pid)
+    }
+
+    /// See [`Analyzer::detach_app`]
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Analyzer::detach_app`]
+    pub fn detach_app(&self, pid: Pid) -> Result<()> {
+        self.shared.analyzer().detach_app(pid)
+    }
+
+    /// See [`Analyzer::detach_apps`]
+    pub fn detach_apps(&self) {
+        self.shared.analyzer().detach_apps();
+    }
+
+    /// See [`Analyzer::contains`]
+    #[must_use]
+    pub fn contains(&self, pid: Pid) -> bool {
+        self.shared.analyzer().contains(pid)
+    }
+
+    /// Run `f` with exclusive access to the analyzer, e.g. to record or to receive idle periods.
+    /// Frames received by `f` don't reach the stream
+    pub fn with<R>(&self, f: impl FnOnce(&mut Analyzer) -> R) -> R {
+        with(&self.shared, f)
+    }
+}
+
+impl AsyncAnalyzerHandle {
+    /// Same as [`AsyncAnalyzer::attach_app`]
+    ///
+    /// # Errors
+    ///
+    /// Same as [`AsyncAnalyzer::attach_app`]
+    pub fn attach_app(&self, pid: Pid) -> Result<()> {
+        attach_app(&self.shared, pid)
+    }
+
+    /// Same as [`AsyncAnalyzer::detach_app`]
+    ///
+    /// # Errors
+    ///
+    /// Same as [`AsyncAnalyzer::detach_app`]
+    pub fn detach_app(&self, pid: Pid) -> Result<()> {
+        self.shared.analyzer().detach_app(pid)
+    }
+
+    /// Same as [`AsyncAnalyzer::detach_apps`]
+    pub fn detach_apps(&self) {
+        self.shared.analyzer().detach_apps();
+    }
+
+    /// Same as [`AsyncAnalyzer::contains`]
+    #[must_use]
+    pub fn contains(&self, pid: Pid) -> bool {
+        self.shared.analyzer().contains(pid)
+    }
+
+    /// Same as [`AsyncAnalyzer::with`]
+    pub fn with<R>(&self, f: impl FnOnce(&mut Analyzer) -> R) -> R {
+        with(&self.shared, f)
+    }
+}
+
+fn attach_app(shared: &Shared, pid: Pid) -> Result<()> {
+    shared.analyzer().attach_app(pid)?;
+    // sources without a file descriptor have nothing to wake the stream up
+    shared.waker.wake();
+
+    Ok(())
+}
+
+fn with<R>(shared: &Shared, f: impl FnOnce(&mut Analyzer) -> R) -> R {
+    let result = f(&mut shared.analyzer());
+    // `f` may have attached apps or left frames to receive
+    shared.waker.wake();
+
+    result
+}
+
+impl Stream for AsyncAnalyzer {
+    type Item = FrameEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        this.shared.waker.register(cx.waker());
+
+        loop {
+            {
+                let mut analyzer = this.shared.analyzer();
+                if let Some(event) = analyzer.try_recv() {
+                    return Poll::Ready(Some(event));
+                }
+                if analyzer.ended() {
+                    return Poll::Ready(None);
+                }
+            }
+
+            if let Some(ready) = &this.ready {
+                match ready.poll_read_ready(cx) {
+                    Poll::Ready(Ok(mut guard)) => {
+                        // the source is drained by the next receive
+                        guard.clear_ready();
+                        continue;
+                    }
+                    Poll::Ready(Err(e)) => this.shared.analyzer().error = Some(e.into()),
+                    Poll::Pending => (),
+                }
+            }
+
+            if this.tick.poll_tick(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        os::unix::{
+            io::{AsRawFd, RawFd},
+            net::UnixStream,
+        },
+        time::Duration,
+    };
+
+    use futures::{StreamExt, poll};
+
+    use super::{AsyncAnalyzer, TICK};
+    use crate::{AnalyzerBuilder, FrameSource, Pid, error::Result, event::RawFrame};
+
+    // a frame of pid 1000 for every byte written to the other end
+    struct SocketSource {
+        socket: UnixStream,
+        now_ns: u64,
+    }
+
+    impl FrameSource for SocketSource {
+        fn attach(&mut self, _pid: Pid) -> Result<()> {
+            Ok(())
+        }
+
+        fn detach(&mut self, _pid: Pid) -> Result<()> {
+            Ok(())
+        }
+
+        fn read(&mut self, frames: &mut Vec<RawFrame>, _timeout: Option<Duration>) -> Result<()> {
+            let mut bytes = [0; 16];
+            while let Ok(len @ 1..) = self.socket.read(&mut bytes) {
+                for _ in 0..len {
+                    self.now_ns += 16_000_000;
+                    frames.push(RawFrame {
+                        pid: 1000,
+                        ktime_ns: self.now_ns,
+                        buffer: 0x1,
+                    });
+                }
+            }
+
+            Ok(())
+        }
+
+        fn now_ns(&self) -> u64 {
+            self.now_ns
+        }
+
+        fn ready_fd(&self) -> Option<RawFd> {
+            Some(self.socket.as_raw_fd())
+        }
+    }
+
+    #[tokio::test]
+    async fn wakes_up_on_a_readable_source() {
+        let (socket, mut writer) = UnixStream::pair().unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let source = SocketSource { socket, now_ns: 0 };
+        let mut analyzer =
+            AsyncAnalyzer::new(AnalyzerBuilder::new().source(source).build().unwrap()).unwrap();
+        analyzer.handle().attach_app(1000).unwrap();
+
+        let next = tokio::spawn(async move {
+            let event = analyzer.next().await;
+            (analyzer, event)
+        });
+        tokio::time::sleep(TICK / 5).await;
+        writer.write_all(&[0; 3]).unwrap();
+
+        // woken up well before the next tick
+        let (mut analyzer, event) = tokio::time::timeout(TICK / 2, next).await.unwrap().unwrap();
+        assert_eq!(event.unwrap().frametime, Duration::from_millis(16));
+
+        assert!(analyzer.next().await.is_some());
+        assert!(poll!(analyzer.next()).is_pending());
+    }
+}
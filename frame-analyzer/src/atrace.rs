@@ -0,0 +1,321 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+    time::Duration,
+};
+
+use crate::{Pid, error::Result, event::RawFrame, source::FrameSource};
+
+// frames handed out by one read
+const BATCH: usize = 1024;
+// the slice atrace wraps every `Surface::queueBuffer` in
+const QUEUE_BUFFER: &str = "queueBuffer";
+
+/// Frames of an atrace / systrace capture in the ftrace text format
+///
+/// Every `queueBuffer` slice begun with a `tracing_mark_write: B|<pid>|queueBuffer` marker is a frame of `<pid>`, as the
+/// live analyzer sees every `Surface::queueBuffer` call. A `queueBuffer` nested in another one, as `BufferQueueProducer`'s,
+/// is the same frame. Captures don't tell surfaces apart, the thread queuing the buffer stands in for its surface.
+/// Lines that aren't ftrace events are skipped, so the text of an HTML systrace works as well.
+///
+/// The frames are timestamped with the trace clock of the capture, usually `CLOCK_BOOTTIME` on Android.
+/// They're handed out as fast as they're read, and receiving returns `None` right away once every frame is read, see [`crate::Analyzer::ended`]
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use frame_analyzer::{AnalyzerBuilder, AtraceSource};
+///
+/// # fn main() {
+/// # try_main().unwrap();
+/// # }
+/// #
+/// # fn try_main() -> anyhow::Result<()> {
+/// let capture = "\
+/// # tracer: nop
+/// #
+/// com.example.game-1000  ( 1000) [000] ...1   100.000000: tracing_mark_write: B|1000|Choreographer#doFrame
+///      RenderThread-1010  ( 1000) [001] ...1   100.004000: tracing_mark_write: B|1000|queueBuffer
+///      RenderThread-1010  ( 1000) [001] ...1   100.004100: tracing_mark_write: E|1000
+///      RenderThread-1010  ( 1000) [001] ...1   100.020000: tracing_mark_write: B|1000|queueBuffer
+///      RenderThread-1010  ( 1000) [001] ...1   100.020050: tracing_mark_write: B|1000|queueBuffer
+///      RenderThread-1010  ( 1000) [001] ...1   100.020080: tracing_mark_write: E|1000
+///      RenderThread-1010  ( 1000) [001] ...1   100.020100: tracing_mark_write: E|1000
+///      RenderThread-1010  ( 1000) [001] ...1   100.037000: tracing_mark_write: B|1000|queueBuffer
+///      RenderThread-1010  ( 1000) [001] ...1   100.037100: tracing_mark_write: E|1000
+/// ";
+///
+/// let source = AtraceSource::parse(capture.as_bytes())?;
+/// assert_eq!(source.pids(), [(1000, Some("com.example.game"))]);
+///
+/// let mut analyzer = AnalyzerBuilder::new().source(source).build()?;
+/// analyzer.attach_app(1000)?;
+///
+/// assert_eq!(analyzer.recv(), Some((1000, Duration::from_millis(16))));
+/// assert_eq!(analyzer.recv(), Some((1000, Duration::from_millis(17))));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct AtraceSource {
+    frames: Vec<RawFrame>,
+    names: BTreeMap<Pid, Option<String>>,
+    // the next frame to hand out
+    next: usize,
+    attached: HashSet<Pid>,
+    now_ns: u64,
+}
+
+impl AtraceSource {
+    /// Parse a capture file
+    ///
+    /// # Errors
+    ///
+    /// `IOError` if the file can't be read
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::parse(BufReader::new(File::open(path)?))
+    }
+
+    /// Parse a capture
+    ///
+    /// # Errors
+    ///
+    /// `IOError` if reading fails
+    pub fn parse(mut reader: impl BufRead) -> Result<Self> {
+        let mut source = Self::default();
+        // the slices open on every thread
+        let mut stacks: HashMap<u32, Vec<String>> = HashMap::new();
+        // the task names of the threads, a main thread is named after its process
+        let mut tasks: HashMap<u32, String> = HashMap::new();
+        let mut bytes = Vec::new();
+
+        loop {
+            bytes.clear();
+            if reader.read_until(b'\n', &mut bytes)? == 0 {
+                break;
+            }
+            let line = String::from_utf8_lossy(&bytes);
+            let Some(event) = parse_line(&line) else {
+                continue;
+            };
+
+            tasks
+                .entry(event.tid)
+                .or_insert_with(|| event.task.to_string());
+
+            let stack = stacks.entry(event.tid).or_default();
+            match event.marker {
+                Marker::Begin(pid, name) => {
+                    if name == QUEUE_BUFFER && !stack.iter().any(|open| open == QUEUE_BUFFER) {
+                        source.frames.push(RawFrame {
+                            pid,
+                            ktime_ns: event.timestamp_ns,
+                            buffer: event.tid as usize,
+                        });
+                        source.names.entry(pid).or_default();
+                    }
+                    stack.push(name.to_string());
+                }
+                Marker::End => {
+                    stack.pop();
+                }
+            }
+        }
+
+        for (pid, name) in &mut source.names {
+            *name = u32::try_from(*pid).ok().and_then(|tid| tasks.remove(&tid));
+        }
+        // ftrace sorts events per cpu buffer only
+        source.frames.sort_by_key(|frame| frame.ktime_ns);
+
+        Ok(source)
+    }
+
+    /// The processes that queued buffers, with the task name of their main thread if the capture has it
+    #[must_use]
+    pub fn pids(&self) -> Vec<(Pid, Option<&str>)> {
+        self.names
+            .iter()
+            .map(|(pid, name)| (*pid, name.as_deref()))
+            .collect()
+    }
+
+    /// Every frame of the capture, in time order
+    #[must_use]
+    pub fn frames(&self) -> &[RawFrame] {
+        &self.frames
+    }
+}
+
+impl FrameSource for AtraceSource {
+    fn attach(&mut self, pid: Pid) -> Result<()> {
+        self.attached.insert(pid);
+        Ok(())
+    }
+
+    fn detach(&mut self, pid: Pid) -> Result<()> {
+        self.attached.remove(&pid);
+        Ok(())
+    }
+
+    fn read(&mut self, frames: &mut Vec<RawFrame>, _timeout: Option<Duration>) -> Result<()> {
+        let len = frames.len();
+
+        while frames.len() - len < BATCH {
+            let Some(frame) = self.frames.get(self.next) else {
+                break;
+            };
+            self.next += 1;
+
+            self.now_ns = frame.ktime_ns;
+            if self.attached.contains(&frame.pid) {
+                frames.push(*frame);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn now_ns(&self) -> u64 {
+        self.now_ns
+    }
+
+    fn ended(&self) -> bool {
+        self.next >= self.frames.len()
+    }
+}
+
+enum Marker<'a> {
+    Begin(Pid, &'a str),
+    End,
+}
+
+struct Event<'a> {
+    task: &'a str,
+    tid: u32,
+    timestamp_ns: u64,
+    marker: Marker<'a>,
+}
+
+// `<task>-<tid> (<tgid>) [<cpu>] <flags> <seconds>: tracing_mark_write: B|<pid>|<name>`, the tgid and flags are optional
+fn parse_line(line: &str) -> Option<Event<'_>> {
+    let (prefix, payload) = line.split_once(": tracing_mark_write: ")?;
+    let payload = payload.trim_end();
+
+    let (head, timestamp) = prefix.trim_end().rsplit_once(char::is_whitespace)?;
+    let timestamp_ns = parse_seconds(timestamp)?;
+
+    // the cpu is the last `[<digits>]`, task names may hold anything
+    let cpu = head.rfind(" [")?;
+    let mut task = head[..cpu].trim_end();
+    if task.ends_with(')') {
+        task = task[..task.rfind('(')?].trim_end();
+    }
+    let (task, tid) = task.trim_start().rsplit_once('-')?;
+    let tid = tid.parse().ok()?;
+
+    let mut fields = payload.splitn(3, '|');
+    let marker = match fields.next()? {
+        "B" => Marker::Begin(fields.next()?.parse().ok()?, fields.next()?),
+        // with or without the pid
+        "E" => Marker::End,
+        _ => return None,
+    };
+
+    Some(Event {
+        task,
+        tid,
+        timestamp_ns,
+        marker,
+    })
+}
+
+// `<seconds>.<fraction>`, the fraction has up to 9 digits
+fn parse_seconds(timestamp: &str) -> Option<u64> {
+    let timestamp = timestamp.strip_suffix(':').unwrap_or(timestamp);
+    let (seconds, fraction) = timestamp.split_once('.').unwrap_or((timestamp, ""));
+    if fraction.len() > 9 || !fraction.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    let seconds: u64 = seconds.parse().ok()?;
+    let nanos: u64 = format!("{fraction:0<9}").parse().ok()?;
+    // too far out for nanoseconds, the line is skipped
+    seconds.checked_mul(1_000_000_000)?.checked_add(nanos)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fmt::Write, time::Duration};
+
+    use super::{AtraceSource, BATCH, parse_seconds};
+    use crate::AnalyzerBuilder;
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(parse_seconds("1234.567890:"), Some(1_234_567_890_000));
+        assert_eq!(parse_seconds("12.000000001"), Some(12_000_000_001));
+        assert_eq!(parse_seconds("12"), Some(12_000_000_000));
+        assert_eq!(parse_seconds("1.0000000001"), None);
+        assert_eq!(parse_seconds("1.-5"), None);
+        // past u64 nanoseconds
+        assert_eq!(parse_seconds("18446744073.709551615"), Some(u64::MAX));
+        assert_eq!(parse_seconds("18446744073.709551616"), None);
+        assert_eq!(parse_seconds("18446744074.0"), None);
+    }
+
+    #[test]
+    fn ends_with_the_capture() {
+        let mut capture = String::new();
+        for i in 0..BATCH as u64 * 2 {
+            let micros = 100_000_000 + i * 16_000;
+            let timestamp = format!("{}.{:06}", micros / 1_000_000, micros % 1_000_000);
+            writeln!(
+                capture,
+                "RenderThread-1010 (1000) [001] ...1 {timestamp}: tracing_mark_write: B|1000|queueBuffer"
+            )
+            .unwrap();
+            writeln!(
+                capture,
+                "RenderThread-1010 (1000) [001] ...1 {timestamp}: tracing_mark_write: E|1000"
+            )
+            .unwrap();
+        }
+
+        let source = AtraceSource::parse(capture.as_bytes()).unwrap();
+        let mut analyzer = AnalyzerBuilder::new().source(source).build().unwrap();
+        analyzer.attach_app(1000).unwrap();
+
+        let mut frames = 0;
+        while !analyzer.ended() {
+            if let Some((_, frametime)) = analyzer.recv() {
+                assert_eq!(frametime, Duration::from_millis(16));
+                frames += 1;
+            }
+        }
+        assert_eq!(frames, BATCH * 2 - 1);
+        assert_eq!(analyzer.recv(), None);
+    }
+}
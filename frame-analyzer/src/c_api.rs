@@ -67,7 +67,12 @@ fn error_to_code(err: &AnalyzerError) -> c_int {
         AnalyzerError::BpfMapError(_) => -3,
         AnalyzerError::IOError(_) => -4,
         AnalyzerError::AppNotFound => -5,
-        AnalyzerError::MapError => -6,
+        AnalyzerError::UprobeAttachError(_) => -6,
+        AnalyzerError::FrameDataReadError(_) => -7,
+        AnalyzerError::AndroidPermissionDenied => -8,
+        AnalyzerError::ConfigError(_) => -9,
+        AnalyzerError::MemlockError(_) => -10,
+        AnalyzerError::RecordingError(_) => -11,
     }
 }
 
@@ -197,29 +202,16 @@ pub extern "C" fn frame_analyzer_try_recv(
     }
     let analyzer = unsafe { &mut *handle };
 
-    // 非阻塞逻辑：直接检查缓冲区，不等待
-    if analyzer.buffer.is_empty() {
-        return 1; // 无数据
-    }
-
-    let p = match analyzer.buffer.pop_front() {
-        Some(pid) => pid,
-        None => return 1,
-    };
-
-    let frametime = match analyzer.map.get_mut(&p) {
-        Some(target) => target.update(),
-        None => return 1,
-    };
-
-    if let Some(t) = frametime {
-        unsafe {
-            *pid = p as c_int;
-            *frametime_ns = t.as_nanos() as u64;
+    // 非阻塞逻辑：轮询一次所有ring，不等待
+    match analyzer.try_recv() {
+        Some(event) => {
+            unsafe {
+                *pid = event.pid as c_int;
+                *frametime_ns = event.frametime.as_nanos() as u64;
+            }
+            0 // 成功
         }
-        0 // 成功
-    } else {
-        1 // 无有效帧时间
+        None => 1, // 无数据
     }
 }
 
@@ -0,0 +1,332 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write as _,
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    path::Path,
+};
+
+use crate::{
+    Pid,
+    config::AnalyzerConfig,
+    error::Result,
+    event::FrameEvent,
+    jank::{JankConfig, JankKind},
+    record::{self, Recording},
+    surface_metrics::SurfaceMetrics,
+};
+
+struct Track {
+    tid: u32,
+    metrics: SurfaceMetrics,
+}
+
+/// Writes frames as Chrome Trace Event JSON, which the Perfetto UI and `chrome://tracing` open directly
+///
+/// Every process is a process of the trace and every surface a thread track of it, with a slice per frame.
+/// A frame ending an idle period is an `idle` slice instead.
+/// Every process gets an `fps` counter with a series per surface, averaged over the last second,
+/// and janks are instant events on the surface track, see [`JankDetector`]
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use frame_analyzer::{AnalyzerBuilder, ChromeTrace, Script, SyntheticSource};
+///
+/// # fn main() {
+/// # try_main().unwrap();
+/// # }
+/// #
+/// # fn try_main() -> anyhow::Result<()> {
+/// let script = Script::new(1000, 0x1)
+/// .frames(60, Duration::from_micros(16_667))
+/// .frames(1, Duration::from_millis(200))
+/// .frames(60, Duration::from_micros(16_667));
+/// let source = SyntheticSource::new().script(script);
+/// let mut analyzer = AnalyzerBuilder::new().source(source).build()?;
+/// analyzer.attach_app(1000)?;
+///
+/// let mut trace = ChromeTrace::new(Vec::new())?;
+/// trace.process_name(1000, "com.example.game")?;
+/// while let Some(event) = analyzer.recv_event() {
+/// trace.push(&event)?;
+/// }
+///
+/// let json = String::from_utf8(trace.finish()?)?;
+/// assert_eq!(json.matches(r#""name":"frame""#).count(), 121);
+/// assert!(json.contains(r#""name":"BigJank""#));
+/// # Ok(())
+/// # }
+/// ```
+///
+/// A recording, see [`ChromeTrace::write_recording`]
+///
+/// ```
+/// use frame_analyzer::{AnalyzerConfig, ChromeTrace, Recording};
+///
+/// # fn main() {
+/// # let _ = try_main();
+/// # }
+/// #
+/// # fn try_main() -> anyhow::Result<()> {
+/// let mut recording = Recording::open("session.frec")?;
+/// let mut trace = ChromeTrace::create("session.json")?;
+/// trace.write_recording(&mut recording, &AnalyzerConfig::default())?;
+/// trace.finish()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct ChromeTrace<W: Write> {
+    // `None` once finished
+    writer: Option<W>,
+    jank: JankConfig,
+    tracks: HashMap<(Pid, usize), Track>,
+    named: HashSet<Pid>,
+    first: bool,
+    line: String,
+}
+
+impl ChromeTrace<BufWriter<File>> {
+    /// Write the trace into a new file
+    ///
+    /// # Errors
+    ///
+    /// `IOError` if the file can't be created
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> ChromeTrace<W> {
+    /// Write the trace into `writer`
+    ///
+    /// # Errors
+    ///
+    /// `IOError` if the start of the trace can't be written
+    pub fn new(mut writer: W) -> Result<Self> {
+        writer.write_all(b"{\"displayTimeUnit\":\"ms\",\"traceEvents\":[\n")?;
+
+        Ok(Self {
+            writer: Some(writer),
+            jank: JankConfig::default(),
+            tracks: HashMap::new(),
+            named: HashSet::new(),
+            first: true,
+            line: String::new(),
+        })
+    }
+
+    /// The thresholds of the jank markers of the surfaces seen from now on, [`JankConfig::default`] by default
+    #[must_use]
+    pub const fn jank_config(mut self, config: JankConfig) -> Self {
+        self.jank = config;
+        self
+    }
+
+    /// Name the process `pid` in the trace, e.g. with its package name
+    ///
+    /// # Errors
+    ///
+    /// `IOError` if writing fails
+    pub fn process_name(&mut self, pid: Pid, name: &str) -> Result<()> {
+        self.named.insert(pid);
+
+        let _ = write!(
+            self.line,
+            r#"{{"ph":"M","name":"process_name","pid":{pid},"args":{{"name":"#
+        );
+        push_json_str(&mut self.line, name);
+        self.line.push_str("}}");
+        self.emit()
+    }
+
+    /// Add a frame
+    ///
+    /// # Errors
+    ///
+    /// `IOError` if writing fails
+    pub fn push(&mut self, event: &FrameEvent) -> Result<()> {
+        let next_tid = u32::try_from(self.tracks.len() + 1).unwrap_or(u32::MAX);
+        let mut new_track = false;
+        let track = self
+            .tracks
+            .entry((event.pid, event.surface))
+            .or_insert_with(|| {
+                new_track = true;
+                Track {
+                    tid: next_tid,
+                    metrics: SurfaceMetrics::new(self.jank),
+                }
+            });
+        let tid = track.tid;
+        let metrics = track.metrics.push(event);
+        let jank = metrics.jank.and_then(|frame| frame.kind);
+        let fps = metrics.fps;
+
+        let pid = event.pid;
+        let surface = event.surface;
+        let end = event.timestamp_ns;
+        let start = end.saturating_sub(event.frametime.as_nanos() as u64);
+
+        if new_track {
+            let _ = write!(
+                self.line,
+                r#"{{"ph":"M","name":"thread_name","pid":{pid},"tid":{tid},"args":{{"name":"surface {surface:#x}"}}}}"#
+            );
+            self.emit()?;
+        }
+
+        let (name, color) = match (event.after_idle, jank) {
+            (true, _) => ("idle", "grey"),
+            (false, Some(JankKind::BigJank)) => ("frame", "terrible"),
+            (false, Some(JankKind::Jank)) => ("frame", "bad"),
+            (false, None) => ("frame", "good"),
+        };
+        let _ = write!(
+            self.line,
+            r#"{{"ph":"X","name":"{name}","cat":"frame","cname":"{color}","pid":{pid},"tid":{tid},"ts":{},"dur":{},"args":{{"seq":{},"frametime_ms":{:.3}}}}}"#,
+            Micros(start),
+            Micros(end - start),
+            event.seq,
+            event.frametime.as_secs_f64() * 1000.0,
+        );
+        self.emit()?;
+
+        if let Some(kind) = jank {
+            let _ = write!(
+                self.line,
+                r#"{{"ph":"i","s":"t","name":"{kind:?}","cat":"jank","pid":{pid},"tid":{tid},"ts":{},"args":{{"frametime_ms":{:.3}}}}}"#,
+                Micros(end),
+                event.frametime.as_secs_f64() * 1000.0,
+            );
+            self.emit()?;
+        }
+
+        if let Some(fps) = fps {
+            let _ = write!(
+                self.line,
+                r#"{{"ph":"C","name":"fps","pid":{pid},"ts":{},"args":{{"{surface:#x}":{fps:.2}}}}}"#,
+                Micros(end),
+            );
+            self.emit()?;
+        }
+
+        Ok(())
+    }
+
+    /// Add every frame of a recording, as analyzed by an [`crate::Analyzer`] configured with `config`
+    ///
+    /// The recorded processes are named with their cmdlines
+    ///
+    /// # Errors
+    ///
+    /// `IOError` if reading or writing fails and `RecordingError` if the recording is damaged
+    pub fn write_recording<R: Read>(
+        &mut self,
+        recording: &mut Recording<R>,
+        config: &AnalyzerConfig,
+    ) -> Result<()> {
+        self.name_recorded(recording)?;
+        record::analyze(recording, config, |event| self.push(event))?;
+        // processes attached later in the recording
+        self.name_recorded(recording)
+    }
+
+    fn name_recorded<R: Read>(&mut self, recording: &Recording<R>) -> Result<()> {
+        for (pid, cmdline) in recording.pids() {
+            if !self.named.contains(&pid) && !cmdline.is_empty() {
+                self.process_name(pid, cmdline)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// End the trace, returning the writer
+    ///
+    /// Dropping the trace ends it too, ignoring errors
+    ///
+    /// # Errors
+    ///
+    /// `IOError` if writing fails
+    pub fn finish(mut self) -> Result<W> {
+        let mut writer = self
+            .writer
+            .take()
+            .ok_or_else(|| io::Error::other("trace already finished"))?;
+        writer.write_all(b"\n]}\n")?;
+        writer.flush()?;
+
+        Ok(writer)
+    }
+
+    fn emit(&mut self) -> Result<()> {
+        let Some(writer) = self.writer.as_mut() else {
+            return Ok(());
+        };
+        if !self.first {
+            writer.write_all(b",\n")?;
+        }
+        self.first = false;
+
+        let result = writer.write_all(self.line.as_bytes());
+        self.line.clear();
+        Ok(result?)
+    }
+}
+
+impl<W: Write> Drop for ChromeTrace<W> {
+    fn drop(&mut self) {
+        if let Some(writer) = &mut self.writer {
+            let _ = writer.write_all(b"\n]}\n");
+            let _ = writer.flush();
+        }
+    }
+}
+
+// nanoseconds formatted as the microseconds of the trace
+struct Micros(u64);
+
+impl std::fmt::Display for Micros {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{:03}", self.0 / 1000, self.0 % 1000)
+    }
+}
+
+fn push_json_str(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
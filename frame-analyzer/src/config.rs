@@ -0,0 +1,402 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{fmt, time::Duration};
+#[cfg(feature = "serde")]
+use std::{fs, path::Path};
+
+use frame_analyzer_ebpf_common::{CLOCK_BOOTTIME, CLOCK_MONOTONIC};
+
+use crate::{
+    Analyzer,
+    error::Result,
+    event::IDLE_GAP,
+    source::{EbpfSource, FrameSource},
+};
+
+/// A function the ebpf program is attached to, each call of it is one frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ProbeTarget {
+    /// Path of the library containing the function
+    pub library: String,
+    /// The (mangled) symbol of the function, its first argument identifies the surface
+    pub symbol: String,
+}
+
+impl ProbeTarget {
+    /// Probe `symbol` in `library`
+    pub fn new(library: impl Into<String>, symbol: impl Into<String>) -> Self {
+        Self {
+            library: library.into(),
+            symbol: symbol.into(),
+        }
+    }
+
+    /// `android::Surface::queueBuffer` of `/system/lib64/libgui.so`, both the old and the new signature
+    #[must_use]
+    pub fn libgui() -> Vec<Self> {
+        vec![
+            Self::new(
+                "/system/lib64/libgui.so",
+                "_ZN7android7Surface11queueBufferEP19ANativeWindowBufferi",
+            ),
+            Self::new(
+                "/system/lib64/libgui.so",
+                "_ZN7android7Surface11queueBufferEP19ANativeWindowBufferiPNS_24SurfaceQueueBufferOutputE",
+            ),
+        ]
+    }
+}
+
+/// The clock the ebpf program timestamps frames with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ClockSource {
+    /// `CLOCK_MONOTONIC`, stops while the device is suspended
+    #[default]
+    Monotonic,
+    /// `CLOCK_BOOTTIME`, keeps counting while the device is suspended
+    Boottime,
+}
+
+impl ClockSource {
+    pub(crate) const fn id(self) -> u32 {
+        match self {
+            Self::Monotonic => CLOCK_MONOTONIC,
+            Self::Boottime => CLOCK_BOOTTIME,
+        }
+    }
+}
+
+/// The current time of `clock` in nanoseconds, comparable with [`crate::FrameEvent::timestamp_ns`]
+pub fn now_ns(clock: ClockSource) -> u64 {
+    clock_ns(match clock {
+        ClockSource::Monotonic => libc::CLOCK_MONOTONIC,
+        ClockSource::Boottime => libc::CLOCK_BOOTTIME,
+    })
+}
+
+/// The current time of any clock in nanoseconds
+pub fn clock_ns(clock: libc::clockid_t) -> u64 {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(clock, &raw mut now) };
+
+    now.tv_sec as u64 * 1_000_000_000 + now.tv_nsec as u64
+}
+
+/// Which surfaces of an app produce frames
+///
+/// An app may queue buffers on several surfaces at once (e.g. a game and a video ad), usually only one of them is interesting
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SurfaceSelection {
+    /// The surface with the most frames in its history, and the shortest total frametime among those
+    #[default]
+    LongestHistory,
+    /// The surface that queued its first buffer last
+    Newest,
+    /// Every surface, tell them apart with [`crate::FrameEvent::surface`]
+    All,
+}
+
+/// What to do with `RLIMIT_MEMLOCK` when the analyzer is built
+///
+/// Kernels without memcg based accounting of ebpf memory need it raised, see <https://lwn.net/Articles/837122/>.
+/// Nothing is changed until an analyzer is built, failing to raise the limit fails the build with `MemlockError`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Memlock {
+    /// Raise the limit to infinity for the rest of the process
+    #[default]
+    Unlimited,
+    /// Raise the limit to infinity and restore the previous one once every analyzer built with this is dropped.
+    /// The limit isn't restored if an analyzer built with [`Memlock::Unlimited`] raised it meanwhile
+    Scoped,
+    /// Leave the limit alone
+    Unchanged,
+}
+
+/// The configuration of an [`Analyzer`], see [`AnalyzerBuilder`]
+///
+/// Loadable from JSON with the `serde` feature, missing fields take their default
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct AnalyzerConfig {
+    /// How many frametimes of every surface are kept to select surfaces
+    pub history: usize,
+    /// Size in bytes of the ring the ebpf program passes frames through, a power of 2 multiple of the page size
+    pub ring_size: u32,
+    /// How many ready rings one wait of the analyzer picks up
+    pub event_capacity: usize,
+    /// The functions to attach to, tried in order until one attaches
+    pub probes: Vec<ProbeTarget>,
+    /// The clock frames are timestamped with
+    pub clock: ClockSource,
+    /// Which surfaces of an app produce frames
+    pub surface_selection: SurfaceSelection,
+    /// What to do with `RLIMIT_MEMLOCK`
+    pub memlock: Memlock,
+    /// Surfaces that don't queue a frame for this long are idle rather than slow
+    pub idle_threshold: Duration,
+    /// Write atrace markers for every frame into the kernel trace
+    pub trace_marker: bool,
+}
+
+impl Default for AnalyzerConfig {
+    fn default() -> Self {
+        Self {
+            history: 144,
+            ring_size: 256 * 1024,
+            event_capacity: 1024,
+            probes: ProbeTarget::libgui(),
+            clock: ClockSource::default(),
+            surface_selection: SurfaceSelection::default(),
+            memlock: Memlock::default(),
+            idle_threshold: IDLE_GAP,
+            trace_marker: false,
+        }
+    }
+}
+
+impl AnalyzerConfig {
+    /// Fails with `ConfigError` if the ebpf program can't work with the configuration
+    pub(crate) fn validate(&self) -> Result<()> {
+        let page_size = page_size();
+
+        // the page size is a power of 2 as well
+        if !self.ring_size.is_power_of_two() || self.ring_size < page_size {
+            return Err(crate::AnalyzerError::ConfigError(format!(
+                "ring_size {} isn't a power of 2 multiple of the page size ({page_size})",
+                self.ring_size
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+fn page_size() -> u32 {
+    u32::try_from(unsafe { libc::sysconf(libc::_SC_PAGESIZE) }).unwrap_or(4096)
+}
+
+/// Builds an [`Analyzer`] with explicit configuration
+///
+/// # Examples
+///
+/// ```
+/// use frame_analyzer::{AnalyzerBuilder, ClockSource, SurfaceSelection};
+///
+/// # fn main() {
+/// # let _ = try_main();
+/// # }
+/// #
+/// # fn try_main() -> anyhow::Result<()> {
+/// let analyzer = AnalyzerBuilder::new()
+///     .history(240)
+///     .ring_size(512 * 1024)
+///     .clock(ClockSource::Boottime)
+///     .surface_selection(SurfaceSelection::All)
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct AnalyzerBuilder {
+    config: AnalyzerConfig,
+    source: Option<Box<dyn FrameSource>>,
+}
+
+impl fmt::Debug for AnalyzerBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AnalyzerBuilder")
+            .field("config", &self.config)
+            .field("source", &self.source.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
+
+impl AnalyzerBuilder {
+    /// Start from the default configuration, the one of [`Analyzer::new`]
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start from `config`
+    #[must_use]
+    pub const fn from_config(config: AnalyzerConfig) -> Self {
+        Self {
+            config,
+            source: None,
+        }
+    }
+
+    /// Start from a JSON file holding an [`AnalyzerConfig`], available with the `serde` feature
+    ///
+    /// ```json
+    /// { "history": 240, "clock": "Boottime", "surface_selection": "All" }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns `IOError` if the file can't be read and `ConfigError` if it isn't a valid configuration
+    #[cfg(feature = "serde")]
+    pub fn from_json_file(path: impl AsRef<Path>) -> Result<Self> {
+        let json = fs::read_to_string(path)?;
+        let config = serde_json::from_str(&json)
+            .map_err(|e| crate::AnalyzerError::ConfigError(e.to_string()))?;
+
+        Ok(Self::from_config(config))
+    }
+
+    /// How many frametimes of every surface are kept to select surfaces, 144 by default
+    #[must_use]
+    pub const fn history(mut self, history: usize) -> Self {
+        self.config.history = history;
+        self
+    }
+
+    /// Size in bytes of the ebpf ring of every attached app, 256 KiB by default
+    ///
+    /// A power of 2 multiple of the page size, building fails with `ConfigError` otherwise
+    #[must_use]
+    pub const fn ring_size(mut self, ring_size: u32) -> Self {
+        self.config.ring_size = ring_size;
+        self
+    }
+
+    /// How many ready rings one wait of the analyzer picks up, 1024 by default
+    #[must_use]
+    pub const fn event_capacity(mut self, event_capacity: usize) -> Self {
+        self.config.event_capacity = event_capacity;
+        self
+    }
+
+    /// The functions to attach to, tried in order until one attaches. [`ProbeTarget::libgui`] by default
+    #[must_use]
+    pub fn probes(mut self, probes: impl IntoIterator<Item = ProbeTarget>) -> Self {
+        self.config.probes = probes.into_iter().collect();
+        self
+    }
+
+    /// The clock frames are timestamped with, [`ClockSource::Monotonic`] by default
+    #[must_use]
+    pub const fn clock(mut self, clock: ClockSource) -> Self {
+        self.config.clock = clock;
+        self
+    }
+
+    /// Which surfaces of an app produce frames, [`SurfaceSelection::LongestHistory`] by default
+    #[must_use]
+    pub const fn surface_selection(mut self, surface_selection: SurfaceSelection) -> Self {
+        self.config.surface_selection = surface_selection;
+        self
+    }
+
+    /// What to do with `RLIMIT_MEMLOCK`, [`Memlock::Unlimited`] by default
+    #[must_use]
+    pub const fn memlock(mut self, memlock: Memlock) -> Self {
+        self.config.memlock = memlock;
+        self
+    }
+
+    /// Surfaces that don't queue a frame for this long are idle rather than slow, [`IDLE_GAP`] by default
+    ///
+    /// The frame ending an idle period is marked [`crate::FrameEvent::after_idle`], see [`crate::IdleEvent`]
+    #[must_use]
+    pub const fn idle_threshold(mut self, idle_threshold: Duration) -> Self {
+        self.config.idle_threshold = idle_threshold;
+        self
+    }
+
+    /// Write atrace markers for every received frame into `trace_marker`, off by default
+    ///
+    /// Frames, frametimes, fps and janks show up in the process of the app in any systrace or Perfetto capture taken meanwhile.
+    /// Markers are silently left out if the marker file is missing or can't be written, e.g. without root
+    #[must_use]
+    pub const fn trace_marker(mut self, enabled: bool) -> Self {
+        self.config.trace_marker = enabled;
+        self
+    }
+
+    /// Where the frames come from, an [`EbpfSource`] probing the apps on the device by default
+    ///
+    /// The probe options and the [`Memlock`] policy only apply to the default source
+    #[must_use]
+    pub fn source(mut self, source: impl FrameSource + 'static) -> Self {
+        self.source = Some(Box::new(source));
+        self
+    }
+
+    /// The configuration built so far
+    #[must_use]
+    pub const fn config(&self) -> &AnalyzerConfig {
+        &self.config
+    }
+
+    /// Build the analyzer, applying the [`Memlock`] policy if there's no other source
+    ///
+    /// # Errors
+    ///
+    /// `ConfigError` if the ring size isn't a power of 2 multiple of the page size, whatever the source.
+    /// Same as [`Analyzer::new`] if there's no other source
+    pub fn build(self) -> Result<Analyzer> {
+        self.config.validate()?;
+        let source = match self.source {
+            Some(source) => source,
+            None => Box::new(EbpfSource::new(self.config.clone())?),
+        };
+
+        Ok(Analyzer::with_source(self.config, source))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AnalyzerBuilder, AnalyzerConfig};
+    use crate::{AnalyzerError, SyntheticSource};
+
+    #[test]
+    fn rejects_bad_ring_sizes() {
+        for ring_size in [0, 1000, 3 * 4096, 1024] {
+            let result = AnalyzerBuilder::new().ring_size(ring_size).build();
+            assert!(
+                matches!(result, Err(AnalyzerError::ConfigError(_))),
+                "{ring_size}"
+            );
+        }
+        // whatever the source
+        let result = AnalyzerBuilder::new()
+            .ring_size(1000)
+            .source(SyntheticSource::new())
+            .build();
+        assert!(matches!(result, Err(AnalyzerError::ConfigError(_))));
+
+        let config = AnalyzerConfig {
+            ring_size: 1024 * 1024,
+            ..AnalyzerConfig::default()
+        };
+        assert!(config.validate().is_ok());
+        assert!(AnalyzerConfig::default().validate().is_ok());
+    }
+}
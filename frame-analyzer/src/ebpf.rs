@@ -16,34 +16,174 @@
  * You should have received a copy of the GNU General Public License
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
-use aya::{Ebpf, include_bytes_aligned};
-use ctor::ctor;
+use std::{io, sync::Mutex};
 
-use crate::error::Result;
+use aya::{Ebpf, EbpfLoader, include_bytes_aligned};
 
-#[ctor]
-fn ebpf_workround() {
+use crate::{
+    config::{AnalyzerConfig, Memlock},
+    error::{AnalyzerError, Result},
+};
+
+struct Raised {
+    holders: usize,
+    previous: Option<libc::rlimit>,
+}
+
+// shared by all analyzers with `Memlock::Scoped`, the limit is restored once the last of them is dropped.
+// `Memlock::Unlimited` forgets the previous limit, so it's never lowered under such an analyzer
+static RAISED: Mutex<Raised> = Mutex::new(Raised {
+    holders: 0,
+    previous: None,
+});
+
+/// Keeps `RLIMIT_MEMLOCK` raised while alive, see [`Memlock::Scoped`]
+pub struct MemlockGuard(());
+
+impl Drop for MemlockGuard {
+    fn drop(&mut self) {
+        let mut raised = RAISED
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        raised.holders -= 1;
+
+        if raised.holders > 0 {
+            return;
+        }
+
+        if let Some(previous) = raised.previous.take() {
+            unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &raw const previous) };
+        }
+    }
+}
+
+/// Apply the `RLIMIT_MEMLOCK` policy of the configuration
+///
+/// Returns a guard restoring the previous limit for [`Memlock::Scoped`]
+pub fn apply_memlock(memlock: Memlock) -> Result<Option<MemlockGuard>> {
+    match memlock {
+        Memlock::Unchanged => Ok(None),
+        Memlock::Unlimited => {
+            let mut raised = RAISED
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
+
+            raise_memlock()?;
+            raised.previous = None;
+            drop(raised);
+
+            Ok(None)
+        }
+        Memlock::Scoped => {
+            let mut raised = RAISED
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
+
+            if raised.holders == 0 {
+                let mut previous = libc::rlimit {
+                    rlim_cur: 0,
+                    rlim_max: 0,
+                };
+                if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &raw mut previous) } != 0 {
+                    return Err(AnalyzerError::MemlockError(io::Error::last_os_error()));
+                }
+
+                raise_memlock()?;
+                raised.previous = Some(previous);
+            }
+
+            raised.holders += 1;
+            drop(raised);
+
+            Ok(Some(MemlockGuard(())))
+        }
+    }
+}
+
+fn raise_memlock() -> Result<()> {
     // Bump the memlock rlimit. This is needed for older kernels that don't use the
     // new memcg based accounting, see https://lwn.net/Articles/837122/
     let rlim = libc::rlimit {
         rlim_cur: libc::RLIM_INFINITY,
         rlim_max: libc::RLIM_INFINITY,
     };
-    unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &rlim) };
+
+    if unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &raw const rlim) } == 0 {
+        Ok(())
+    } else {
+        Err(AnalyzerError::MemlockError(io::Error::last_os_error()))
+    }
 }
 
-pub fn load_bpf() -> Result<Ebpf> {
+pub fn load_bpf(config: &AnalyzerConfig) -> Result<Ebpf> {
+    let clock = config.clock.id();
+    let mut loader = EbpfLoader::new();
+    loader
+        .set_max_entries("RING_BUF", config.ring_size)
+        .set_global("CLOCK_SOURCE", &clock, true);
+
     // This will include eBPF object file as raw bytes at compile-time and load it at runtime.
     #[cfg(debug_assertions)]
-    let bpf = Ebpf::load(include_bytes_aligned!(concat!(
+    let bpf = loader.load(include_bytes_aligned!(concat!(
         env!("OUT_DIR"),
         "/ebpf_target/bpfel-unknown-none/debug/frame-analyzer-ebpf"
     )))?;
     #[cfg(not(debug_assertions))]
-    let bpf = Ebpf::load(include_bytes_aligned!(concat!(
+    let bpf = loader.load(include_bytes_aligned!(concat!(
         env!("OUT_DIR"),
         "/ebpf_target/bpfel-unknown-none/release/frame-analyzer-ebpf"
     )))?;
 
     Ok(bpf)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::apply_memlock;
+    use crate::config::Memlock;
+
+    fn memlock() -> libc::rlimit {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &raw mut limit) };
+        limit
+    }
+
+    fn set_memlock(limit: libc::rlimit) {
+        unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &raw const limit) };
+    }
+
+    // puts the limit back once the test is over, also when it fails
+    struct Restore(libc::rlimit);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            set_memlock(self.0);
+        }
+    }
+
+    #[test]
+    fn scoped_restores_unless_raised_for_good() {
+        let original = Restore(memlock());
+        let low = libc::rlimit {
+            rlim_cur: 64 * 1024,
+            ..original.0
+        };
+        set_memlock(low);
+
+        // raising the hard limit needs CAP_SYS_RESOURCE
+        let Ok(scoped) = apply_memlock(Memlock::Scoped) else {
+            return;
+        };
+        assert_eq!(memlock().rlim_cur, libc::RLIM_INFINITY);
+        drop(scoped);
+        assert_eq!(memlock().rlim_cur, low.rlim_cur);
+
+        let scoped = apply_memlock(Memlock::Scoped).unwrap();
+        apply_memlock(Memlock::Unlimited).unwrap();
+        drop(scoped);
+        assert_eq!(memlock().rlim_cur, libc::RLIM_INFINITY);
+    }
+}
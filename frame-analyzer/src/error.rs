@@ -57,4 +57,16 @@ pub enum AnalyzerError {
     /// 安卓权限不足（补充安卓平台特有错误）
     #[error("Insufficient permissions on Android (need root or CAP_BPF)")]
     AndroidPermissionDenied,
+
+    /// 分析器配置无效（如JSON配置文件解析失败）
+    #[error("Invalid analyzer configuration: {0}")]
+    ConfigError(String),
+
+    /// 调整`RLIMIT_MEMLOCK`失败（通常是缺少`CAP_SYS_RESOURCE`）
+    #[error("Failed to raise RLIMIT_MEMLOCK: {0}")]
+    MemlockError(#[source] io::Error),
+
+    /// 录制文件无效或已损坏
+    #[error("Invalid frame recording: {0}")]
+    RecordingError(String),
 }
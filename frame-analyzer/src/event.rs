@@ -0,0 +1,62 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::time::Duration;
+
+use crate::Pid;
+
+/// The default idle threshold, gaps between two frames of a surface at least this long are idle periods rather than frames
+///
+/// See [`crate::AnalyzerBuilder::idle_threshold`]
+pub const IDLE_GAP: Duration = Duration::from_secs(1);
+
+/// A frame signal as it comes out of the ebpf program, before the analysis
+///
+/// Serializable with the `serde` feature
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RawFrame {
+    /// The process that queued the buffer
+    pub pid: Pid,
+    /// When the buffer was queued, in nanoseconds of the configured [`crate::ClockSource`]
+    pub ktime_ns: u64,
+    /// The surface that queued the buffer, the address of its `android::Surface`
+    pub buffer: usize,
+}
+
+/// A frame rendered by an attached application
+///
+/// Serializable with the `serde` feature
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FrameEvent {
+    /// The process that rendered the frame
+    pub pid: Pid,
+    /// When the frame was queued, in nanoseconds of the configured [`crate::ClockSource`] (`CLOCK_MONOTONIC` by default)
+    pub timestamp_ns: u64,
+    /// Time since the previous frame of the same surface
+    pub frametime: Duration,
+    /// The surface that queued the frame, the address of its `android::Surface`
+    pub surface: usize,
+    /// Index of the frame within its surface, counting from the first frame seen
+    pub seq: u64,
+    /// Whether this is the first frame after the surface didn't draw for at least the idle threshold ([`IDLE_GAP`] by default)
+    ///
+    /// Its frametime is the idle period rather than how long the frame took, statistics should leave it out
+    pub after_idle: bool,
+}
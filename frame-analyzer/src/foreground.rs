@@ -0,0 +1,355 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{
+    collections::HashSet,
+    ffi::CString,
+    fs, io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use crate::{Pid, error::Result};
+
+/// The cpuset Android puts the foreground app into
+pub const TOP_APP_CPUSET: &str = "/dev/cpuset/top-app";
+
+// How often an inotify watcher drains its queue while `Analyzer` waits for frames
+const INOTIFY_RECHECK: Duration = Duration::from_millis(100);
+// How often an inotify watcher re-reads the cpuset anyway, processes that exit or move to another cpuset don't show up in inotify
+const INOTIFY_RESCAN: Duration = Duration::from_secs(1);
+
+/// How [`ForegroundWatcher`] notices changes of the foreground cpuset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchMethod {
+    /// Re-read `cgroup.procs` when inotify reports a change in the cpuset directory, and every second for the processes leaving it unreported
+    Inotify,
+    /// Re-read `cgroup.procs` at a fixed interval
+    Polling(Duration),
+}
+
+/// A frame received while following the foreground app
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ForegroundFrame {
+    /// The process that rendered the frame
+    pub pid: Pid,
+    /// The frametime
+    pub frametime: Duration,
+    /// The process that was in the foreground when the frame was received
+    pub foreground: Option<Pid>,
+}
+
+impl ForegroundFrame {
+    /// Whether the frame was rendered by the foreground process
+    #[must_use]
+    pub fn is_foreground(&self) -> bool {
+        self.foreground == Some(self.pid)
+    }
+}
+
+/// Watches the processes of the foreground cpuset
+///
+/// Pass it to [`crate::Analyzer::follow_foreground`] to attach to every process that enters the cpuset and detach from it once it leaves
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use frame_analyzer::{ForegroundWatcher, WatchMethod};
+///
+/// let watcher = ForegroundWatcher::new()
+///     .root("/dev/cpuset/top-app")
+///     .method(WatchMethod::Polling(Duration::from_millis(500)));
+/// ```
+#[derive(Debug)]
+pub struct ForegroundWatcher {
+    root: PathBuf,
+    method: WatchMethod,
+    inotify: Option<OwnedFd>,
+    last_scan: Option<Instant>,
+    members: Vec<Pid>,
+    // the members kept attached, also when a uid group attached them first
+    pub(crate) attached: HashSet<Pid>,
+}
+
+impl Default for ForegroundWatcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ForegroundWatcher {
+    /// Watch [`TOP_APP_CPUSET`] with inotify
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            root: PathBuf::from(TOP_APP_CPUSET),
+            method: WatchMethod::Inotify,
+            inotify: None,
+            last_scan: None,
+            members: Vec::new(),
+            attached: HashSet::new(),
+        }
+    }
+
+    /// Watch the cpuset at `root` instead, `root/cgroup.procs` is read for its members
+    #[must_use]
+    pub fn root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.root = root.into();
+        self
+    }
+
+    /// Change how the cpuset is watched
+    #[must_use]
+    pub const fn method(mut self, method: WatchMethod) -> Self {
+        self.method = method;
+        self
+    }
+
+    /// The processes currently in the cpuset, in the order they entered it
+    pub fn members(&self) -> impl Iterator<Item = Pid> + '_ {
+        self.members.iter().copied()
+    }
+
+    pub(crate) fn start(&mut self) -> Result<()> {
+        if self.method == WatchMethod::Inotify {
+            let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+            let root = CString::new(self.root.as_os_str().as_bytes())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let mask = libc::IN_MODIFY | libc::IN_CLOSE_WRITE | libc::IN_CREATE | libc::IN_MOVED_TO;
+            if unsafe { libc::inotify_add_watch(fd.as_raw_fd(), root.as_ptr(), mask) } < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+
+            self.inotify = Some(fd);
+        }
+
+        self.last_scan = None;
+        Ok(())
+    }
+
+    /// Re-read the cpuset if it may have changed, returns the processes that entered and left it
+    pub(crate) fn refresh(&mut self) -> Result<(Vec<Pid>, Vec<Pid>)> {
+        let due = match (self.method, self.last_scan) {
+            (_, None) => true,
+            (WatchMethod::Inotify, Some(last)) => {
+                self.drain_inotify()? || last.elapsed() >= INOTIFY_RESCAN
+            }
+            (WatchMethod::Polling(interval), Some(last)) => last.elapsed() >= interval,
+        };
+
+        if !due {
+            return Ok((Vec::new(), Vec::new()));
+        }
+
+        self.last_scan = Some(Instant::now());
+        let current = self.read_procs()?;
+
+        let left: Vec<Pid> = self
+            .members
+            .iter()
+            .copied()
+            .filter(|pid| !current.contains(pid))
+            .collect();
+        self.members.retain(|pid| current.contains(pid));
+
+        let entered: Vec<Pid> = current
+            .into_iter()
+            .filter(|pid| !self.members.contains(pid))
+            .collect();
+        self.members.extend(&entered);
+
+        Ok((entered, left))
+    }
+
+    /// The longest time `Analyzer` may wait for frames before the cpuset has to be checked again
+    pub(crate) fn wait_hint(&self) -> Duration {
+        match (self.method, self.last_scan) {
+            (WatchMethod::Inotify, _) => INOTIFY_RECHECK,
+            (WatchMethod::Polling(interval), Some(last)) => interval.saturating_sub(last.elapsed()),
+            (WatchMethod::Polling(_), None) => Duration::ZERO,
+        }
+    }
+
+    /// The process that entered the cpuset last among those `attached` to
+    pub(crate) fn foreground(&self, attached: impl Fn(Pid) -> bool) -> Option<Pid> {
+        self.members
+            .iter()
+            .rev()
+            .copied()
+            .find(|pid| attached(*pid))
+    }
+
+    fn drain_inotify(&self) -> Result<bool> {
+        let Some(ref fd) = self.inotify else {
+            return Ok(false);
+        };
+
+        let mut changed = false;
+        let mut buf = [0u8; 4096];
+        loop {
+            let len = unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+            if len > 0 {
+                changed = true;
+                continue;
+            }
+
+            let err = io::Error::last_os_error();
+            if len == 0 || err.kind() == io::ErrorKind::WouldBlock {
+                return Ok(changed);
+            }
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err.into());
+            }
+        }
+    }
+
+    fn read_procs(&self) -> Result<Vec<Pid>> {
+        let procs = fs::read_to_string(self.root.join("cgroup.procs"))?;
+        let mut seen = HashSet::new();
+
+        Ok(procs
+            .lines()
+            .filter_map(|line| line.trim().parse::<Pid>().ok())
+            .filter(|pid| seen.insert(*pid))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::PathBuf,
+        time::{Duration, Instant},
+    };
+
+    use super::{ForegroundWatcher, INOTIFY_RESCAN, WatchMethod};
+    use crate::{AnalyzerBuilder, Script, SyntheticSource};
+
+    struct Cpuset(PathBuf);
+
+    impl Cpuset {
+        fn new(name: &str) -> Self {
+            let root =
+                std::env::temp_dir().join(format!("frame-analyzer-{name}-{}", std::process::id()));
+            fs::create_dir_all(&root).unwrap();
+            Self(root)
+        }
+
+        fn set(&self, procs: &str) {
+            fs::write(self.0.join("cgroup.procs"), procs).unwrap();
+        }
+    }
+
+    impl Drop for Cpuset {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn follows_polled_cpuset() {
+        let cpuset = Cpuset::new("follow");
+        cpuset.set("1000\n");
+
+        let period = Duration::from_micros(16_667);
+        let source = SyntheticSource::new()
+            .script(Script::new(1000, 0x1).frames(60, period))
+            .script(Script::new(2000, 0x2).frames(60, period));
+        let mut analyzer = AnalyzerBuilder::new().source(source).build().unwrap();
+        analyzer
+            .follow_foreground(
+                ForegroundWatcher::new()
+                    .root(&cpuset.0)
+                    .method(WatchMethod::Polling(Duration::ZERO)),
+            )
+            .unwrap();
+
+        assert!(analyzer.contains(1000));
+        assert!(!analyzer.contains(2000));
+        assert_eq!(analyzer.foreground(), Some(1000));
+
+        // the app is switched, the next receive notices it
+        cpuset.set("2000\n");
+        let frame = analyzer.recv_foreground().unwrap();
+        assert!(!analyzer.contains(1000));
+        assert!(analyzer.contains(2000));
+        assert_eq!(frame.pid, 2000);
+        assert!(frame.is_foreground());
+
+        analyzer.unfollow_foreground();
+        assert_eq!(analyzer.pids().count(), 0);
+        assert_eq!(analyzer.foreground(), None);
+    }
+
+    #[test]
+    fn keeps_apps_attached_by_hand() {
+        let cpuset = Cpuset::new("by-hand");
+        cpuset.set("1000\n2000\n");
+
+        let mut analyzer = AnalyzerBuilder::new()
+            .source(SyntheticSource::new())
+            .build()
+            .unwrap();
+        analyzer.attach_app(1000).unwrap();
+        analyzer
+            .follow_foreground(
+                ForegroundWatcher::new()
+                    .root(&cpuset.0)
+                    .method(WatchMethod::Polling(Duration::ZERO)),
+            )
+            .unwrap();
+        assert!(analyzer.contains(2000));
+        assert_eq!(analyzer.foreground(), Some(2000));
+
+        cpuset.set("");
+        assert_eq!(analyzer.try_recv(), None);
+        assert!(analyzer.contains(1000));
+        assert!(!analyzer.contains(2000));
+    }
+
+    #[test]
+    fn rescans_an_inotify_watched_cpuset() {
+        let cpuset = Cpuset::new("rescan");
+        cpuset.set("1000\n2000\n");
+
+        let mut watcher = ForegroundWatcher::new().root(&cpuset.0);
+        watcher.start().unwrap();
+        assert_eq!(watcher.refresh().unwrap(), (vec![1000, 2000], vec![]));
+
+        // as if 2000 exited, cgroupfs doesn't report that
+        cpuset.set("1000\n");
+        watcher.drain_inotify().unwrap();
+        assert_eq!(watcher.refresh().unwrap(), (vec![], vec![]));
+
+        watcher.last_scan = Instant::now().checked_sub(INOTIFY_RESCAN);
+        assert_eq!(watcher.refresh().unwrap(), (vec![], vec![2000]));
+        assert_eq!(watcher.members().collect::<Vec<_>>(), [1000]);
+    }
+}
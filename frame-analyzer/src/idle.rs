@@ -0,0 +1,259 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use crate::{Pid, event::FrameEvent};
+
+/// A surface stopped or started drawing again, see [`crate::Analyzer::try_recv_idle`]
+///
+/// Serializable with the `serde` feature
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum IdleEvent {
+    /// The surface didn't queue a frame for the idle threshold, e.g. the app shows a static screen
+    Start {
+        /// The process owning the surface
+        pid: Pid,
+        /// The surface, see [`FrameEvent::surface`]
+        surface: usize,
+        /// When the last frame before the idle period was queued
+        timestamp_ns: u64,
+    },
+    /// The surface queued a frame again, that frame is the [`FrameEvent`] marked `after_idle`
+    End {
+        /// The process owning the surface
+        pid: Pid,
+        /// The surface, see [`FrameEvent::surface`]
+        surface: usize,
+        /// When the frame ending the idle period was queued
+        timestamp_ns: u64,
+        /// How long the surface was idle, the gap between the two frames
+        idle: Duration,
+    },
+}
+
+impl IdleEvent {
+    /// The process owning the surface
+    #[must_use]
+    pub const fn pid(&self) -> Pid {
+        match self {
+            Self::Start { pid, .. } | Self::End { pid, .. } => *pid,
+        }
+    }
+
+    /// The surface that went idle or drew again
+    #[must_use]
+    pub const fn surface(&self) -> usize {
+        match self {
+            Self::Start { surface, .. } | Self::End { surface, .. } => *surface,
+        }
+    }
+}
+
+struct Activity {
+    timestamp_ns: u64,
+    idle: bool,
+}
+
+/// Turns the frames of every surface into idle periods
+pub struct IdleTracker {
+    threshold: Duration,
+    capacity: usize,
+    surfaces: HashMap<(Pid, usize), Activity>,
+    events: VecDeque<IdleEvent>,
+}
+
+impl IdleTracker {
+    /// Keeps the latest `capacity` events that haven't been received
+    pub fn new(threshold: Duration, capacity: usize) -> Self {
+        Self {
+            threshold,
+            capacity,
+            surfaces: HashMap::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Record a frame, ending the idle period of its surface if it comes after one
+    pub fn track(&mut self, event: &FrameEvent) {
+        let previous = self.surfaces.insert(
+            (event.pid, event.surface),
+            Activity {
+                timestamp_ns: event.timestamp_ns,
+                idle: false,
+            },
+        );
+
+        if !event.after_idle {
+            return;
+        }
+
+        // the start wasn't noticed if no one received between the two frames
+        if previous.as_ref().is_none_or(|activity| !activity.idle) {
+            self.push(IdleEvent::Start {
+                pid: event.pid,
+                surface: event.surface,
+                timestamp_ns: event
+                    .timestamp_ns
+                    .saturating_sub(event.frametime.as_nanos() as u64),
+            });
+        }
+
+        self.push(IdleEvent::End {
+            pid: event.pid,
+            surface: event.surface,
+            timestamp_ns: event.timestamp_ns,
+            idle: event.frametime,
+        });
+    }
+
+    /// Start the idle periods of the surfaces that didn't draw for the threshold until `now_ns`
+    pub fn check(&mut self, now_ns: u64) {
+        let threshold = self.threshold.as_nanos() as u64;
+        let mut started = Vec::new();
+
+        for ((pid, surface), activity) in &mut self.surfaces {
+            if !activity.idle && now_ns.saturating_sub(activity.timestamp_ns) >= threshold {
+                activity.idle = true;
+                started.push(IdleEvent::Start {
+                    pid: *pid,
+                    surface: *surface,
+                    timestamp_ns: activity.timestamp_ns,
+                });
+            }
+        }
+
+        for event in started {
+            self.push(event);
+        }
+    }
+
+    fn push(&mut self, event: IdleEvent) {
+        if self.events.len() >= self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    /// Take the oldest event that hasn't been received
+    pub fn pop(&mut self) -> Option<IdleEvent> {
+        self.events.pop_front()
+    }
+
+    /// Forget the surfaces of a detached process
+    pub fn forget(&mut self, pid: Pid) {
+        self.surfaces
+            .retain(|(surface_pid, _), _| *surface_pid != pid);
+    }
+
+    /// Forget every surface
+    pub fn forget_all(&mut self) {
+        self.surfaces.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{IdleEvent, IdleTracker};
+    use crate::{event::FrameEvent, test_util::event};
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn frame(surface: usize, timestamp_ns: u64, frametime: Duration) -> FrameEvent {
+        FrameEvent {
+            timestamp_ns,
+            surface,
+            ..event(frametime, frametime >= Duration::from_secs(1))
+        }
+    }
+
+    #[test]
+    fn starts_once_the_threshold_passed() {
+        let mut idle = IdleTracker::new(Duration::from_secs(1), 16);
+        idle.track(&frame(0x1, SECOND, Duration::from_millis(16)));
+
+        idle.check(SECOND + SECOND / 2);
+        assert_eq!(idle.pop(), None);
+
+        idle.check(2 * SECOND);
+        assert_eq!(
+            idle.pop(),
+            Some(IdleEvent::Start {
+                pid: 1,
+                surface: 0x1,
+                timestamp_ns: SECOND,
+            })
+        );
+        // noticed once
+        idle.check(3 * SECOND);
+        assert_eq!(idle.pop(), None);
+
+        idle.track(&frame(0x1, 4 * SECOND, Duration::from_secs(3)));
+        assert_eq!(
+            idle.pop(),
+            Some(IdleEvent::End {
+                pid: 1,
+                surface: 0x1,
+                timestamp_ns: 4 * SECOND,
+                idle: Duration::from_secs(3),
+            })
+        );
+    }
+
+    #[test]
+    fn starts_late_if_nobody_checked() {
+        let mut idle = IdleTracker::new(Duration::from_secs(1), 16);
+        idle.track(&frame(0x1, SECOND, Duration::from_millis(16)));
+        idle.track(&frame(0x1, 3 * SECOND, Duration::from_secs(2)));
+
+        assert!(matches!(
+            idle.pop(),
+            Some(IdleEvent::Start { timestamp_ns, .. }) if timestamp_ns == SECOND
+        ));
+        assert!(matches!(idle.pop(), Some(IdleEvent::End { .. })));
+        assert_eq!(idle.pop(), None);
+    }
+
+    #[test]
+    fn keeps_the_latest_events() {
+        let mut idle = IdleTracker::new(Duration::from_secs(1), 2);
+        for surface in 0..4 {
+            idle.track(&frame(surface, 0, Duration::from_millis(16)));
+        }
+        idle.track(&FrameEvent {
+            pid: 2,
+            ..frame(0x10, 0, Duration::from_millis(16))
+        });
+        idle.forget(2);
+        idle.check(SECOND);
+
+        let events: Vec<_> = std::iter::from_fn(|| idle.pop()).collect();
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|event| event.pid() == 1));
+
+        idle.forget_all();
+        idle.check(10 * SECOND);
+        assert_eq!(idle.pop(), None);
+    }
+}
@@ -0,0 +1,353 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+#![allow(clippy::cast_precision_loss)]
+
+use std::{collections::VecDeque, time::Duration};
+
+use crate::event::FrameEvent;
+
+/// How bad a jank is, in the `PerfDog` sense
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum JankKind {
+    /// Longer than [`JankConfig::jank_threshold`]
+    Jank,
+    /// Longer than [`JankConfig::big_jank_threshold`]
+    BigJank,
+}
+
+/// The thresholds of a [`JankDetector`], the defaults are the ones of `PerfDog`
+///
+/// Serializable with the `serde` feature
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct JankConfig {
+    /// How many previous frames the mean is taken over, 3 by default
+    pub history: usize,
+    /// A jank is longer than this many times the mean of the previous frames, 2 by default
+    pub multiplier: f64,
+    /// A jank is also longer than this, two frames of a 24 fps movie (83.33ms) by default
+    pub jank_threshold: Duration,
+    /// A big jank is also longer than this, three frames of a 24 fps movie (125ms) by default
+    pub big_jank_threshold: Duration,
+    /// The refresh period frames are expected in, Android-style janky frames are only counted if set
+    pub target_period: Option<Duration>,
+    /// A janky frame is longer than the target period by more than this fraction of it, 0.5 by default
+    ///
+    /// i.e. it missed at least one vsync
+    pub target_tolerance: f64,
+}
+
+impl Default for JankConfig {
+    fn default() -> Self {
+        Self {
+            history: 3,
+            multiplier: 2.0,
+            jank_threshold: Duration::from_nanos(83_333_333),
+            big_jank_threshold: Duration::from_millis(125),
+            target_period: None,
+            target_tolerance: 0.5,
+        }
+    }
+}
+
+impl JankConfig {
+    /// Count Android-style janky frames against `fps`
+    #[must_use]
+    pub fn target_fps(mut self, fps: u32) -> Self {
+        self.target_period = (fps > 0).then(|| Duration::from_secs(1) / fps);
+        self
+    }
+}
+
+/// What a [`JankDetector`] makes of one frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FrameJank {
+    /// The frametime of the frame
+    pub frametime: Duration,
+    /// Whether the frame is a `PerfDog`-style jank
+    pub kind: Option<JankKind>,
+    /// Whether the frame missed the target period, always `false` without [`JankConfig::target_period`]
+    pub janky: bool,
+}
+
+/// Jank counts of a whole session
+///
+/// Serializable with the `serde` feature
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct JankTotals {
+    /// Every frame of the session
+    pub frames: u64,
+    /// Frametimes of every frame added up
+    pub total_time: Duration,
+    /// Janks, big janks included
+    pub janks: u64,
+    /// Big janks
+    pub big_janks: u64,
+    /// Frametimes of the janks added up, big janks included
+    pub jank_time: Duration,
+    /// Frames that missed the target period
+    pub janky_frames: u64,
+}
+
+impl JankTotals {
+    /// How much of the session was spent in janks, the `PerfDog` stutter rate
+    #[must_use]
+    pub fn stutter(&self) -> f64 {
+        if self.total_time.is_zero() {
+            return 0.0;
+        }
+
+        self.jank_time.as_secs_f64() / self.total_time.as_secs_f64()
+    }
+
+    /// Janks per hour of the session, the way `PerfDog` normalizes jank counts
+    #[must_use]
+    pub fn janks_per_hour(&self) -> f64 {
+        if self.total_time.is_zero() {
+            return 0.0;
+        }
+
+        self.janks as f64 * 3600.0 / self.total_time.as_secs_f64()
+    }
+
+    /// The fraction of the frames that missed the target period
+    #[must_use]
+    pub fn janky_ratio(&self) -> f64 {
+        if self.frames == 0 {
+            return 0.0;
+        }
+
+        self.janky_frames as f64 / self.frames as f64
+    }
+}
+
+/// Detects janks in the frames of one surface and keeps the totals of the session
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use frame_analyzer::{JankConfig, JankDetector, JankKind};
+///
+/// let mut detector = JankDetector::new(JankConfig::default().target_fps(60));
+/// for _ in 0..3 {
+/// detector.push(Duration::from_millis(16));
+/// }
+///
+/// let frame = detector.push(Duration::from_millis(100));
+/// assert_eq!(frame.kind, Some(JankKind::Jank));
+/// assert!(frame.janky);
+/// assert_eq!(detector.totals().janks, 1);
+/// ```
+#[derive(Debug, Clone)]
+pub struct JankDetector {
+    config: JankConfig,
+    previous: VecDeque<Duration>,
+    totals: JankTotals,
+}
+
+impl Default for JankDetector {
+    fn default() -> Self {
+        Self::new(JankConfig::default())
+    }
+}
+
+impl JankDetector {
+    /// Create a detector starting a new session
+    #[must_use]
+    pub fn new(config: JankConfig) -> Self {
+        Self {
+            config,
+            previous: VecDeque::with_capacity(config.history),
+            totals: JankTotals::default(),
+        }
+    }
+
+    /// The thresholds of the detector
+    #[must_use]
+    pub const fn config(&self) -> &JankConfig {
+        &self.config
+    }
+
+    /// Judge a frame and add it to the totals
+    ///
+    /// The first frames are never janks, until there are enough previous frames to take the mean over
+    pub fn push(&mut self, frametime: Duration) -> FrameJank {
+        let kind = self.jank_kind(frametime);
+        let janky = self.config.target_period.is_some_and(|period| {
+            frametime.as_secs_f64() > period.as_secs_f64() * (1.0 + self.config.target_tolerance)
+        });
+
+        self.totals.frames += 1;
+        self.totals.total_time += frametime;
+        if let Some(kind) = kind {
+            self.totals.janks += 1;
+            self.totals.jank_time += frametime;
+            if kind == JankKind::BigJank {
+                self.totals.big_janks += 1;
+            }
+        }
+        if janky {
+            self.totals.janky_frames += 1;
+        }
+
+        if self.previous.len() >= self.config.history {
+            self.previous.pop_front();
+        }
+        if self.config.history > 0 {
+            self.previous.push_back(frametime);
+        }
+
+        FrameJank {
+            frametime,
+            kind,
+            janky,
+        }
+    }
+
+    /// Judge the frame of an event
+    ///
+    /// A frame after an idle gap isn't judged, it starts over the previous frames instead
+    pub fn push_event(&mut self, event: &FrameEvent) -> Option<FrameJank> {
+        if event.after_idle {
+            self.previous.clear();
+            return None;
+        }
+
+        Some(self.push(event.frametime))
+    }
+
+    fn jank_kind(&self, frametime: Duration) -> Option<JankKind> {
+        if self.config.history == 0 || self.previous.len() < self.config.history {
+            return None;
+        }
+
+        let mean =
+            self.previous.iter().sum::<Duration>().as_secs_f64() / self.previous.len() as f64;
+        if frametime.as_secs_f64() <= mean * self.config.multiplier {
+            return None;
+        }
+
+        if frametime > self.config.big_jank_threshold {
+            Some(JankKind::BigJank)
+        } else if frametime > self.config.jank_threshold {
+            Some(JankKind::Jank)
+        } else {
+            None
+        }
+    }
+
+    /// The totals of the session so far
+    #[must_use]
+    pub const fn totals(&self) -> &JankTotals {
+        &self.totals
+    }
+
+    /// End the session, returning its totals
+    pub fn reset(&mut self) -> JankTotals {
+        self.previous.clear();
+        std::mem::take(&mut self.totals)
+    }
+}
+
+impl Extend<Duration> for JankDetector {
+    fn extend<I: IntoIterator<Item = Duration>>(&mut self, iter: I) {
+        for frametime in iter {
+            self.push(frametime);
+        }
+    }
+}
+
+impl Extend<FrameEvent> for JankDetector {
+    fn extend<I: IntoIterator<Item = FrameEvent>>(&mut self, iter: I) {
+        for event in iter {
+            self.push_event(&event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{JankConfig, JankDetector, JankKind};
+    use crate::test_util::event;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn needs_the_history_first() {
+        let mut detector = JankDetector::default();
+        assert_eq!(detector.push(ms(200)).kind, None);
+        assert_eq!(detector.push(ms(16)).kind, None);
+        assert_eq!(detector.push(ms(16)).kind, None);
+
+        // mean of 200, 16 and 16 is 77.3ms, 150ms isn't twice that
+        assert_eq!(detector.push(ms(150)).kind, None);
+    }
+
+    #[test]
+    fn tells_janks_from_big_janks() {
+        let mut detector = JankDetector::default();
+        detector.extend([ms(16); 3]);
+
+        assert_eq!(detector.push(ms(100)).kind, Some(JankKind::Jank));
+        detector.extend([ms(16); 3]);
+        assert_eq!(detector.push(ms(130)).kind, Some(JankKind::BigJank));
+        detector.extend([ms(16); 3]);
+        // twice the mean, but not longer than two frames of a movie
+        assert_eq!(detector.push(ms(80)).kind, None);
+
+        let totals = detector.reset();
+        assert_eq!(totals.frames, 12);
+        assert_eq!(totals.janks, 2);
+        assert_eq!(totals.big_janks, 1);
+        assert_eq!(totals.jank_time, ms(230));
+        assert!((totals.stutter() - 0.23 / totals.total_time.as_secs_f64()).abs() < 1e-9);
+        assert_eq!(detector.totals().frames, 0);
+    }
+
+    #[test]
+    fn counts_janky_frames_against_the_target() {
+        let mut detector = JankDetector::new(JankConfig::default().target_fps(60));
+        detector.extend([ms(16), ms(24), ms(26), ms(33)]);
+
+        assert_eq!(detector.totals().janky_frames, 2);
+        assert!((detector.totals().janky_ratio() - 0.5).abs() < f64::EPSILON);
+        assert!(!JankDetector::default().push(ms(100)).janky);
+    }
+
+    #[test]
+    fn starts_over_after_idle() {
+        let mut detector = JankDetector::default();
+        detector.extend([ms(16); 3].map(|frametime| event(frametime, false)));
+        assert_eq!(detector.push_event(&event(ms(5000), true)), None);
+        // no history to take the mean over
+        let frame = detector.push_event(&event(ms(100), false)).unwrap();
+        assert_eq!(frame.kind, None);
+        assert_eq!(detector.totals().frames, 4);
+    }
+}
@@ -78,32 +78,85 @@
 //! ```
 
 mod analyze_target;
+#[cfg(feature = "tokio")]
+mod async_analyzer;
+mod atrace;
+mod chrome_trace;
 // 关键修改1：将内部模块声明改为公开导出，供外部直接访问
-pub mod c_api; 
+pub mod c_api;
+mod config;
 mod ebpf;
 mod error;
+mod event;
+mod foreground;
+mod idle;
+mod jank;
+mod pacing;
+mod perfetto;
+mod record;
+mod replay;
+mod shared;
+mod source;
+mod stats;
+mod surface_metrics;
+mod synthetic;
+mod table;
+mod target_fps;
+#[cfg(test)]
+mod test_util;
+mod trace_marker;
+mod uid;
 mod uprobe;
 
 use std::{
     collections::{HashMap, VecDeque},
-    os::unix::io::AsRawFd,
-    time::Duration,
+    fs::File,
+    io::Write,
+    mem,
+    path::Path,
+    thread,
+    time::{Duration, Instant},
 };
 
-use mio::{Events, Interest, Poll, Token, event::Event, unix::SourceFd};
-
 use analyze_target::AnalyzeTarget;
+#[cfg(feature = "tokio")]
+pub use async_analyzer::{AsyncAnalyzer, AsyncAnalyzerHandle};
+pub use atrace::AtraceSource;
+pub use chrome_trace::ChromeTrace;
+pub use config::{
+    AnalyzerBuilder, AnalyzerConfig, ClockSource, Memlock, ProbeTarget, SurfaceSelection,
+};
 pub use error::AnalyzerError;
 use error::Result;
-use uprobe::UprobeHandler;
+pub use event::{FrameEvent, IDLE_GAP, RawFrame};
+pub use foreground::{ForegroundFrame, ForegroundWatcher, TOP_APP_CPUSET, WatchMethod};
+pub use idle::IdleEvent;
+use idle::IdleTracker;
+pub use jank::{FrameJank, JankConfig, JankDetector, JankKind, JankTotals};
+pub use pacing::{PacingMeter, PacingMetrics};
+pub use perfetto::PerfettoTrace;
+use record::Recorder;
+pub use record::Recording;
+pub use replay::{Pace, ReplaySource};
+pub use shared::{Backpressure, CallbackGuard, Filter, SharedAnalyzer, Subscription};
+pub use source::{EbpfSource, FrameSource};
+pub use stats::{FrameStats, FrameSummary, KeyedFrameStats, Window};
+pub use synthetic::{Script, SyntheticSource};
+pub use table::{Column, Rotation, TableFormat, TableSink};
+pub use target_fps::{
+    KeyedTargetFps, TargetChange, TargetFps, TargetFpsConfig, TargetFpsEstimator,
+};
+use trace_marker::TraceMarker;
+pub use uid::Uid;
+use uid::UidGroup;
 
 /// The pid of the target application
 pub type Pid = i32;
 
-const EVENT_MAX: usize = 1024;
-
 /// The Frame Analyzer
 ///
+/// Frames are received on the calling thread, share it with [`SharedAnalyzer`] to have them handed to callbacks by a reader thread, see [`SharedAnalyzer::on_frame`]
+///
 /// # Examples
 ///
 /// ```
@@ -126,18 +179,28 @@ const EVENT_MAX: usize = 1024;
 /// # }
 /// ```
 pub struct Analyzer {
-    poll: Option<Poll>,
+    source: Box<dyn FrameSource>,
     map: HashMap<Pid, AnalyzeTarget>,
-    buffer: VecDeque<Pid>,
+    buffer: VecDeque<RawFrame>,
+    // reused by every read of the source
+    read: Vec<RawFrame>,
+    config: AnalyzerConfig,
+    idle: IdleTracker,
+    trace_marker: Option<TraceMarker>,
+    recorder: Option<Recorder>,
+    foreground: Option<ForegroundWatcher>,
+    uids: HashMap<Uid, UidGroup>,
+    // the last failed read of the source, until `take_error`
+    error: Option<AnalyzerError>,
 }
 
 impl Analyzer {
-    /// Create a new analyzer
+    /// Create a new analyzer with the default configuration, see [`AnalyzerBuilder`] to change it
     ///
     /// # Errors
     ///
-    /// This function will make a syscall to the operating system to create the system selector. If this syscall fails, `Analyzer::new` will return with the error.
-    /// See [mio Poll](https://docs.rs/mio/0.8.11/mio/poll/struct.Poll.html) docs for more details.
+    /// Same as [`EbpfSource::new`], the source of the frames.
+    /// See [mio Poll](https://docs.rs/mio/0.8.11/mio/poll/struct.Poll.html) docs for more details about creating the system selector.
     ///
     /// # Examples
     /// ```
@@ -154,11 +217,31 @@ impl Analyzer {
     /// # }
     /// ```
     pub fn new() -> Result<Self> {
-        let poll = None;
+        AnalyzerBuilder::new().build()
+    }
+
+    pub(crate) fn with_source(config: AnalyzerConfig, source: Box<dyn FrameSource>) -> Self {
         let map = HashMap::new();
-        let buffer = VecDeque::with_capacity(EVENT_MAX);
+        let buffer = VecDeque::with_capacity(config.event_capacity);
+        let read = Vec::with_capacity(config.event_capacity);
+        let idle = IdleTracker::new(config.idle_threshold, config.event_capacity);
+        let trace_marker = config
+            .trace_marker
+            .then(|| TraceMarker::open(config.idle_threshold));
 
-        Ok(Self { poll, map, buffer })
+        Self {
+            source,
+            map,
+            buffer,
+            read,
+            config,
+            idle,
+            trace_marker,
+            recorder: None,
+            foreground: None,
+            uids: HashMap::new(),
+            error: None,
+        }
     }
 
     /// Attach the Analyzer to the target application
@@ -169,7 +252,7 @@ impl Analyzer {
     /// `Analyzer::attach_app` will return an error in these cases
     ///
     /// - Target application is not 64-bit
-    /// - Target application is not using /system/lib64/libgui.so (this will only happen if you use this crate on a non-Android platform)
+    /// - None of the configured [`ProbeTarget`]s can be attached, e.g. the target application is not using /system/lib64/libgui.so (this will only happen if you use this crate on a non-Android platform)
     /// - Current user does not have enough permissions to load the built-in ebpf program into the kernel, in which case it will return `BpfProgramError`
     ///
     /// # Examples
@@ -193,9 +276,12 @@ impl Analyzer {
             return Ok(());
         }
 
-        let uprobe = UprobeHandler::attach_app(pid)?;
-        self.map.insert(pid, AnalyzeTarget::new(uprobe));
-        self.register_poll()?;
+        self.source.attach(pid)?;
+        self.map.insert(pid, AnalyzeTarget::new(pid, &self.config));
+
+        if let Some(recorder) = &mut self.recorder {
+            recorder.attach(pid);
+        }
 
         Ok(())
     }
@@ -231,10 +317,12 @@ impl Analyzer {
         }
 
         self.map.remove(&pid).ok_or(AnalyzerError::AppNotFound)?;
-        self.buffer.retain(|pid_event| *pid_event != pid);
-        self.register_poll()?;
-
-        Ok(())
+        self.buffer.retain(|frame| frame.pid != pid);
+        self.idle.forget(pid);
+        if let Some(trace_marker) = &mut self.trace_marker {
+            trace_marker.forget(pid);
+        }
+        self.source.detach(pid)
     }
 
     /// Detach the Analyzer from all attached apps
@@ -259,12 +347,29 @@ impl Analyzer {
     /// # }
     /// ```
     pub fn detach_apps(&mut self) {
+        for pid in self.map.keys() {
+            let _ = self.source.detach(*pid);
+        }
+
         self.map.clear();
         self.buffer.clear();
+        self.idle.forget_all();
+        if let Some(trace_marker) = &mut self.trace_marker {
+            trace_marker.forget_all();
+        }
+
+        if let Some(ref mut watcher) = self.foreground {
+            watcher.attached.clear();
+        }
+
+        for group in self.uids.values_mut() {
+            group.attached.clear();
+        }
     }
 
     /// Attempts to wait for a frametime value on this analyzer
     /// `Analyzer::recv` will always block the current thread if there is no data available
+    /// The frame ending an idle period isn't a frametime, it's reported by [`Analyzer::try_recv_idle`] instead
     ///
     /// # Examples
     /// ```
@@ -289,21 +394,13 @@ impl Analyzer {
     /// # }
     /// ```
     pub fn recv(&mut self) -> Option<(Pid, Duration)> {
-        if self.buffer.is_empty() {
-            if let Some(ref mut poll) = self.poll {
-                let mut events = Events::with_capacity(EVENT_MAX);
-                let _ = poll.poll(&mut events, None);
-
-                self.buffer.extend(events.iter().map(event_to_pid));
+        // the frame after an idle period is reported by `try_recv_idle` instead
+        loop {
+            let event = self.recv_event()?;
+            if !event.after_idle {
+                return Some((event.pid, event.frametime));
             }
-
-            let _ = self.register_poll();
         }
-
-        let pid = self.buffer.pop_front()?;
-        let frametime = self.map.get_mut(&pid)?.update()?;
-
-        Some((pid, frametime))
     }
 
     /// Attempts to wait for a value on this receiver, returning `None` if it waits more than timeout
@@ -333,21 +430,431 @@ impl Analyzer {
     /// # }
     /// ```
     pub fn recv_timeout(&mut self, time: Duration) -> Option<(Pid, Duration)> {
+        let deadline = Instant::now() + time;
+
+        loop {
+            let time = deadline.saturating_duration_since(Instant::now());
+            let event = self.recv_event_timeout(time)?;
+            if !event.after_idle {
+                return Some((event.pid, event.frametime));
+            }
+        }
+    }
+
+    /// Attempts to wait for a frame on this analyzer, like [`Analyzer::recv`] but returns the whole [`FrameEvent`]
+    ///
+    /// # Examples
+    /// ```
+    /// # use frame_analyzer::Analyzer;
+    /// #
+    /// # fn main() {
+    /// # let _ = try_main();
+    /// # }
+    /// #
+    /// # fn try_main() -> anyhow::Result<()> {
+    /// # let mut analyzer = Analyzer::new()?;
+    /// # let app_pid = 2;
+    /// analyzer.attach_app(app_pid)?;
+    ///
+    /// if let Some(event) = analyzer.recv_event() {
+    /// println!(
+    /// "process: {}, surface: {:#x}, frame #{} at {}ns, frametime: {:?}",
+    /// event.pid, event.surface, event.seq, event.timestamp_ns, event.frametime
+    /// );
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn recv_event(&mut self) -> Option<FrameEvent> {
+        self.wait_events(None);
+        self.next_event()
+    }
+
+    /// Attempts to wait for a frame on this analyzer, like [`Analyzer::recv_timeout`] but returns the whole [`FrameEvent`]
+    ///
+    /// # Examples
+    /// ```
+    /// use std::time::Duration;
+    /// # use frame_analyzer::Analyzer;
+    ///
+    /// # fn main() {
+    /// # let _ = try_main();
+    /// # }
+    /// #
+    /// # fn try_main() -> anyhow::Result<()> {
+    /// # let mut analyzer = Analyzer::new()?;
+    /// # let app_pid = 2;
+    /// analyzer.attach_app(app_pid)?;
+    ///
+    /// if let Some(event) = analyzer.recv_event_timeout(Duration::from_secs(1)) {
+    /// if !event.after_idle {
+    /// println!("process: {}, frametime: {:?}", event.pid, event.frametime);
+    /// }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn recv_event_timeout(&mut self, time: Duration) -> Option<FrameEvent> {
+        self.wait_events(Some(time));
+        self.next_event()
+    }
+
+    /// Follow the foreground app
+    ///
+    /// The `Analyzer` attaches to every process that enters the cpuset watched by `watcher` and detaches from it once it leaves.
+    /// Processes that can't be attached (e.g. they don't render with libgui) are skipped silently.
+    /// Following the foreground makes `Analyzer::recv` return `None` now and then, so the cpuset can be checked between waits
+    ///
+    /// # Errors
+    ///
+    /// `Analyzer::follow_foreground` returns `IOError` if the cpuset can't be watched or read
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use frame_analyzer::{Analyzer, ForegroundWatcher};
+    /// #
+    /// # fn main() {
+    /// # let _ = try_main();
+    /// # }
+    /// #
+    /// # fn try_main() -> anyhow::Result<()> {
+    /// let mut analyzer = Analyzer::new()?;
+    /// analyzer.follow_foreground(ForegroundWatcher::new())?;
+    ///
+    /// if let Some(frame) = analyzer.recv_foreground() {
+    /// println!("process: {}, frametime: {:?}, foreground: {:?}", frame.pid, frame.frametime, frame.foreground);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn follow_foreground(&mut self, mut watcher: ForegroundWatcher) -> Result<()> {
+        self.unfollow_foreground();
+
+        watcher.start()?;
+        self.foreground = Some(watcher);
+        self.sync_foreground()
+    }
+
+    /// Stop following the foreground app, processes attached by [`Analyzer::follow_foreground`] are detached
+    ///
+    /// Processes an attached uid still keeps attached stay attached
+    pub fn unfollow_foreground(&mut self) {
+        if let Some(watcher) = self.foreground.take() {
+            for pid in watcher.attached {
+                if !self.kept(pid) {
+                    let _ = self.detach_app(pid);
+                }
+            }
+        }
+    }
+
+    /// The attached process that is currently in the foreground, if following the foreground app
+    #[must_use]
+    pub fn foreground(&self) -> Option<Pid> {
+        self.foreground
+            .as_ref()?
+            .foreground(|pid| self.contains(pid))
+    }
+
+    /// Like [`Analyzer::recv`], but also reports the foreground process at the time of the frame
+    pub fn recv_foreground(&mut self) -> Option<ForegroundFrame> {
+        let (pid, frametime) = self.recv()?;
+        Some(ForegroundFrame {
+            pid,
+            frametime,
+            foreground: self.foreground(),
+        })
+    }
+
+    /// Like [`Analyzer::recv_timeout`], but also reports the foreground process at the time of the frame
+    pub fn recv_foreground_timeout(&mut self, time: Duration) -> Option<ForegroundFrame> {
+        let (pid, frametime) = self.recv_timeout(time)?;
+        Some(ForegroundFrame {
+            pid,
+            frametime,
+            foreground: self.foreground(),
+        })
+    }
+
+    /// Attempts to receive a frame without blocking, `None` if no frame is ready
+    ///
+    /// # Examples
+    /// ```
+    /// # use frame_analyzer::Analyzer;
+    /// #
+    /// # fn main() {
+    /// # let _ = try_main();
+    /// # }
+    /// #
+    /// # fn try_main() -> anyhow::Result<()> {
+    /// # let mut analyzer = Analyzer::new()?;
+    /// # let app_pid = 2;
+    /// analyzer.attach_app(app_pid)?;
+    ///
+    /// while let Some(event) = analyzer.try_recv() {
+    /// println!("process: {}, frametime: {:?}", event.pid, event.frametime);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn try_recv(&mut self) -> Option<FrameEvent> {
+        self.wait_events(Some(Duration::ZERO));
+        self.next_event()
+    }
+
+    /// Moves every frame that is ready into `events` without blocking, returns how many frames were appended
+    ///
+    /// All rings of the attached apps are emptied in one call, handy for consumers that pick up the pending frames once per tick
+    ///
+    /// # Examples
+    /// ```
+    /// # use frame_analyzer::Analyzer;
+    /// #
+    /// # fn main() {
+    /// # let _ = try_main();
+    /// # }
+    /// #
+    /// # fn try_main() -> anyhow::Result<()> {
+    /// # let mut analyzer = Analyzer::new()?;
+    /// # let app_pid = 2;
+    /// analyzer.attach_app(app_pid)?;
+    ///
+    /// let mut events = Vec::new();
+    /// loop {
+    /// analyzer.drain_into(&mut events);
+    /// for event in events.drain(..) {
+    /// println!("process: {}, frametime: {:?}", event.pid, event.frametime);
+    /// }
+    /// # break;
+    /// // run the rest of the tick...
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn drain_into(&mut self, events: &mut Vec<FrameEvent>) -> usize {
+        let len = events.len();
+
         if self.buffer.is_empty() {
-            if let Some(ref mut poll) = self.poll {
-                let mut events = Events::with_capacity(EVENT_MAX);
-                let _ = poll.poll(&mut events, Some(time));
+            self.wait_events(Some(Duration::ZERO));
+        } else {
+            self.read_source(Some(Duration::ZERO));
+        }
+        events.extend(std::iter::from_fn(|| self.next_event()));
+
+        events.len() - len
+    }
 
-                self.buffer.extend(events.iter().map(event_to_pid));
+    /// Attempts to receive the start or end of an idle period without blocking
+    ///
+    /// A surface that doesn't queue a frame for the idle threshold (see [`AnalyzerBuilder::idle_threshold`]) starts an idle period,
+    /// it's noticed on the next receive after the threshold passed. Its next frame ends it and is marked [`FrameEvent::after_idle`]
+    ///
+    /// # Examples
+    /// ```
+    /// use std::time::Duration;
+    /// # use frame_analyzer::{Analyzer, IdleEvent};
+    ///
+    /// # fn main() {
+    /// # let _ = try_main();
+    /// # }
+    /// #
+    /// # fn try_main() -> anyhow::Result<()> {
+    /// # let mut analyzer = Analyzer::new()?;
+    /// # let app_pid = 2;
+    /// analyzer.attach_app(app_pid)?;
+    ///
+    /// if let Some(frame) = analyzer.recv_timeout(Duration::from_secs(1)) {
+    /// println!("frame: {frame:?}");
+    /// }
+    ///
+    /// while let Some(event) = analyzer.try_recv_idle() {
+    /// match event {
+    /// IdleEvent::Start { pid, .. } => println!("{pid} went idle"),
+    /// IdleEvent::End { pid, idle, .. } => println!("{pid} drew again after {idle:?}"),
+    /// }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn try_recv_idle(&mut self) -> Option<IdleEvent> {
+        self.check_idle();
+        self.idle.pop()
+    }
+
+    /// Record every raw frame read from now on into a file, see [`Recording`]
+    ///
+    /// The header holds the device, the kernel, the probe targets, the clock and the attached processes with their cmdlines.
+    /// Frames are written in checksummed chunks at least every second, so a crash loses at most the last second.
+    /// A recording in progress is finished first
+    ///
+    /// # Errors
+    ///
+    /// `IOError` if the file can't be created or the header can't be written
+    ///
+    /// # Examples
+    /// ```
+    /// # use frame_analyzer::Analyzer;
+    /// #
+    /// # fn main() {
+    /// # let _ = try_main();
+    /// # }
+    /// #
+    /// # fn try_main() -> anyhow::Result<()> {
+    /// # let mut analyzer = Analyzer::new()?;
+    /// # let app_pid = 2;
+    /// analyzer.attach_app(app_pid)?;
+    /// analyzer.record("/data/local/tmp/session.frec")?;
+    ///
+    /// for _ in 0..1000 {
+    /// let _ = analyzer.recv();
+    /// }
+    ///
+    /// analyzer.stop_recording()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn record(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        self.record_to(File::create(path)?)
+    }
+
+    /// Like [`Analyzer::record`], but into any writer
+    ///
+    /// # Errors
+    ///
+    /// `IOError` if the header can't be written
+    pub fn record_to(&mut self, writer: impl Write + Send + 'static) -> Result<()> {
+        self.stop_recording()?;
+        self.recorder = Some(Recorder::new(
+            Box::new(writer),
+            &self.config,
+            self.map.keys().copied(),
+        )?);
+
+        Ok(())
+    }
+
+    /// Write the frames not written yet and end the recording, if any
+    ///
+    /// # Errors
+    ///
+    /// `IOError` if writing failed at some point of the recording, the recording ends at the failure
+    pub fn stop_recording(&mut self) -> Result<()> {
+        self.recorder
+            .take()
+            .map_or(Ok(()), |recorder| Ok(recorder.finish()?))
+    }
+
+    /// Attach the Analyzer to every process of an Android uid
+    ///
+    /// An app often runs several processes (main, `:remote`, webview sandbox, etc.) under one uid.
+    /// The processes of `uid` are looked up in `/proc` now and again while receiving, so processes started later are attached as well.
+    /// Processes that can't be attached (e.g. they don't render anything) are skipped silently
+    ///
+    /// # Errors
+    ///
+    /// `Analyzer::attach_uid` returns `IOError` if `/proc` can't be read
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use frame_analyzer::Analyzer;
+    /// #
+    /// # fn main() {
+    /// # let _ = try_main();
+    /// # }
+    /// #
+    /// # fn try_main() -> anyhow::Result<()> {
+    /// # let app_uid = 10234;
+    /// let mut analyzer = Analyzer::new()?;
+    /// analyzer.attach_uid(app_uid)?;
+    ///
+    /// if let Some((uid, frametime)) = analyzer.recv_uid() {
+    /// println!("uid: {uid}, frametime: {frametime:?}");
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn attach_uid(&mut self, uid: Uid) -> Result<()> {
+        if self.uids.contains_key(&uid) {
+            return Ok(());
+        }
+
+        self.uids.insert(uid, UidGroup::new(uid));
+        let result = self.sync_uids();
+        if result.is_err() {
+            self.detach_uid(uid);
+        }
+
+        result
+    }
+
+    /// Detach the Analyzer from the processes attached by [`Analyzer::attach_uid`]
+    ///
+    /// Processes the foreground watcher or another uid still keeps attached stay attached
+    pub fn detach_uid(&mut self, uid: Uid) {
+        if let Some(group) = self.uids.remove(&uid) {
+            for pid in group.attached {
+                if !self.kept(pid) {
+                    let _ = self.detach_app(pid);
+                }
             }
+        }
+    }
+
+    /// An iterator visiting all attached uids in arbitrary order
+    pub fn uids(&self) -> impl Iterator<Item = Uid> + '_ {
+        self.uids.keys().copied()
+    }
+
+    /// The attached uid the process belongs to
+    #[must_use]
+    pub fn uid_of(&self, pid: Pid) -> Option<Uid> {
+        self.uids
+            .iter()
+            .find(|(_, group)| group.members.contains(&pid))
+            .map(|(uid, _)| *uid)
+    }
 
-            let _ = self.register_poll();
+    /// Like [`Analyzer::recv`], but combines the frames of all processes of an attached uid into one stream
+    ///
+    /// The frametime is the one of the process that rendered the frame, so processes drawing at the same time don't shorten each other's.
+    /// Frames of processes not belonging to an attached uid are skipped
+    pub fn recv_uid(&mut self) -> Option<(Uid, Duration)> {
+        loop {
+            let event = self.recv_event()?;
+            if let Some(frame) = self.combine_uid(&event) {
+                return Some(frame);
+            }
         }
+    }
 
-        let pid = self.buffer.pop_front()?;
-        let frametime = self.map.get_mut(&pid)?.update()?;
+    /// Like [`Analyzer::recv_timeout`], but combines the frames of all processes of an attached uid into one stream
+    pub fn recv_uid_timeout(&mut self, time: Duration) -> Option<(Uid, Duration)> {
+        let deadline = Instant::now() + time;
 
-        Some((pid, frametime))
+        loop {
+            let time = deadline.saturating_duration_since(Instant::now());
+            let event = self.recv_event_timeout(time)?;
+            if let Some(frame) = self.combine_uid(&event) {
+                return Some(frame);
+            }
+        }
+    }
+
+    /// The error of the last failed read of the frame source, if any since the last call
+    ///
+    /// Receiving returns `None` when reading fails, e.g. on a damaged chunk of a replayed recording
+    pub const fn take_error(&mut self) -> Option<AnalyzerError> {
+        self.error.take()
+    }
+
+    /// Whether every frame has been received and the source can't deliver any more, e.g. at the end of a replayed recording
+    ///
+    /// The source is only read while a process is attached. Always `false` for [`EbpfSource`]
+    #[must_use]
+    pub fn ended(&self) -> bool {
+        self.buffer.is_empty() && self.source.ended()
     }
 
     /// Whether the target application has been attached by the `Analyzer`
@@ -361,25 +868,166 @@ impl Analyzer {
         self.map.keys().copied()
     }
 
-    fn register_poll(&mut self) -> Result<()> {
-        let poll = Poll::new()?;
+    fn next_event(&mut self) -> Option<FrameEvent> {
+        while let Some(frame) = self.buffer.pop_front() {
+            let event = self
+                .map
+                .get_mut(&frame.pid)
+                .and_then(|target| target.process(&frame));
 
-        for (pid, handler) in &mut self.map {
-            poll.registry().register(
-                &mut SourceFd(&handler.uprobe.ring()?.as_raw_fd()),
-                Token(*pid as usize),
-                Interest::READABLE,
-            )?;
+            if let Some(event) = event {
+                self.idle.track(&event);
+                if let Some(trace_marker) = &mut self.trace_marker {
+                    trace_marker.frame(&event);
+                }
+                return Some(event);
+            }
+        }
+
+        None
+    }
+
+    fn read_source(&mut self, timeout: Option<Duration>) {
+        let mut read = mem::take(&mut self.read);
+        if let Err(e) = self.source.read(&mut read, timeout) {
+            self.error = Some(e);
+        }
+
+        for frame in &read {
+            // frames read before a detach may still show up
+            if !self.map.contains_key(&frame.pid) {
+                continue;
+            }
+
+            if let Some(recorder) = &mut self.recorder {
+                recorder.record(frame);
+            }
+            self.buffer.push_back(*frame);
+        }
+
+        read.clear();
+        self.read = read;
+    }
+
+    fn check_idle(&mut self) {
+        let now_ns = self.source.now_ns();
+        self.idle.check(now_ns);
+        if let Some(trace_marker) = &mut self.trace_marker {
+            trace_marker.check(now_ns);
+        }
+    }
+
+    fn wait_events(&mut self, timeout: Option<Duration>) {
+        if !self.buffer.is_empty() {
+            return;
+        }
+
+        let _ = self.sync_foreground();
+        let _ = self.sync_uids();
+        self.check_idle();
+
+        let hint = self
+            .foreground
+            .iter()
+            .map(ForegroundWatcher::wait_hint)
+            .chain(self.uids.values().map(UidGroup::wait_hint))
+            .chain(self.recorder.as_ref().and_then(Recorder::wait_hint))
+            .min();
+        let timeout = hint.map_or(timeout, |hint| {
+            Some(timeout.map_or(hint, |time| time.min(hint)))
+        });
+
+        if !self.map.is_empty() {
+            self.read_source(timeout);
+        } else if hint.is_some() {
+            // nothing to poll yet, wait for processes to show up
+            thread::sleep(timeout.unwrap_or_default());
+        }
+
+        if let Some(recorder) = &mut self.recorder {
+            recorder.flush_if_due();
+        }
+    }
+
+    fn sync_foreground(&mut self) -> Result<()> {
+        let Some(mut watcher) = self.foreground.take() else {
+            return Ok(());
+        };
+
+        let result = watcher.refresh().map(|(entered, left)| {
+            for pid in left {
+                if watcher.attached.remove(&pid) && !self.kept(pid) {
+                    let _ = self.detach_app(pid);
+                }
+            }
+
+            for pid in entered {
+                if self.keep(pid) {
+                    watcher.attached.insert(pid);
+                }
+            }
+        });
+
+        self.foreground = Some(watcher);
+        result
+    }
+
+    fn sync_uids(&mut self) -> Result<()> {
+        let uids: Vec<Uid> = self.uids.keys().copied().collect();
+
+        for uid in uids {
+            let Some(mut group) = self.uids.remove(&uid) else {
+                continue;
+            };
+
+            let result = group.refresh().map(|(new, gone)| {
+                for pid in gone {
+                    if group.attached.remove(&pid) && !self.kept(pid) {
+                        let _ = self.detach_app(pid);
+                    }
+                }
+
+                for pid in new {
+                    if self.keep(pid) {
+                        group.attached.insert(pid);
+                    }
+                }
+            });
+
+            self.uids.insert(uid, group);
+            result?;
         }
 
-        self.poll = Some(poll);
         Ok(())
     }
-}
 
-fn event_to_pid(event: &Event) -> Pid {
-    let token = event.token();
-    let Token(pid) = token;
-    pid as Pid
-}
+    // Whether the foreground watcher or a uid group keeps the process attached, the one being synced is taken out
+    fn kept(&self, pid: Pid) -> bool {
+        self.foreground
+            .as_ref()
+            .is_some_and(|watcher| watcher.attached.contains(&pid))
+            || self
+                .uids
+                .values()
+                .any(|group| group.attached.contains(&pid))
+    }
+
+    // Whether a process the foreground watcher or a uid group wants is attached for it.
+    // Processes attached by hand stay the caller's, the ones kept by the others are shared
+    fn keep(&mut self, pid: Pid) -> bool {
+        if self.contains(pid) {
+            self.kept(pid)
+        } else {
+            self.attach_app(pid).is_ok()
+        }
+    }
 
+    fn combine_uid(&self, event: &FrameEvent) -> Option<(Uid, Duration)> {
+        // the frame after an idle period is reported by `try_recv_idle` instead, as in `recv`
+        if event.after_idle {
+            return None;
+        }
+
+        Some((self.uid_of(event.pid)?, event.frametime))
+    }
+}
@@ -0,0 +1,335 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+#![allow(clippy::cast_precision_loss)]
+
+use std::{collections::VecDeque, time::Duration};
+
+use crate::{event::FrameEvent, stats::Window};
+
+/// Pacing of the frames in the window of a [`PacingMeter`]
+///
+/// Serializable with the `serde` feature
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PacingMetrics {
+    /// How many frames are in the window
+    pub frames: usize,
+    /// The average frametime
+    pub mean_frametime: Duration,
+    /// Variance of the differences between consecutive frametimes, in ms²
+    pub delta_variance: f64,
+    /// Root mean square of the differences between consecutive frametimes
+    pub delta_rms: Duration,
+    /// The fraction of the frames within the tolerance of the target period, from 0 to 1
+    pub on_target: f64,
+    /// 1 for perfectly even frames, down to 0 once consecutive frametimes differ by the mean frametime on average
+    pub smoothness: f64,
+}
+
+/// Measures how evenly frames are paced over a sliding [`Window`]
+///
+/// Every metric is kept up to date as frames are pushed, reading them is O(1).
+/// Fed from an [`crate::Analyzer`] with [`PacingMeter::push_event`] or from a recording with any iterator of frametimes
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use frame_analyzer::{PacingMeter, Window};
+///
+/// let target = Duration::from_micros(16_667);
+///
+/// let even: PacingMeter = [target; 120].into_iter().collect();
+/// let uneven: PacingMeter = [Duration::from_millis(8), Duration::from_millis(25)]
+///     .into_iter()
+///     .cycle()
+///     .take(120)
+///     .collect();
+///
+/// // both average ~60 fps
+/// assert!(even.metrics().unwrap().smoothness > 0.99);
+/// assert!(uneven.metrics().unwrap().smoothness < 0.01);
+///
+/// let mut meter = PacingMeter::new(target, Window::Frames(120)).tolerance(0.1);
+/// meter.extend([target, target, Duration::from_millis(25)]);
+/// assert!((meter.metrics().unwrap().on_target - 2.0 / 3.0).abs() < 1e-9);
+/// ```
+#[derive(Debug, Clone)]
+pub struct PacingMeter {
+    target: Duration,
+    tolerance: f64,
+    window: Window,
+    frametimes: VecDeque<Duration>,
+    total: Duration,
+    // in nanoseconds, integers so removing frames doesn't drift
+    delta_sum: i128,
+    delta_squares: i128,
+    on_target: usize,
+}
+
+impl Default for PacingMeter {
+    /// Against 60 fps, over every frame
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1) / 60, Window::All)
+    }
+}
+
+impl PacingMeter {
+    /// Measure frames expected every `target` period over `window`, with a tolerance of 10%
+    #[must_use]
+    pub const fn new(target: Duration, window: Window) -> Self {
+        Self {
+            target,
+            tolerance: 0.1,
+            window,
+            frametimes: VecDeque::new(),
+            total: Duration::ZERO,
+            delta_sum: 0,
+            delta_squares: 0,
+            on_target: 0,
+        }
+    }
+
+    /// Frames within this fraction of the target period are on target, 0.1 by default
+    #[must_use]
+    pub fn tolerance(mut self, tolerance: f64) -> Self {
+        self.tolerance = tolerance;
+        self.recount();
+        self
+    }
+
+    /// Change the target period, e.g. once a [`crate::TargetFpsEstimator`] reports a change
+    pub fn set_target(&mut self, target: Duration) {
+        self.target = target;
+        self.recount();
+    }
+
+    /// The target period
+    #[must_use]
+    pub const fn target(&self) -> Duration {
+        self.target
+    }
+
+    fn recount(&mut self) {
+        self.on_target = self
+            .frametimes
+            .iter()
+            .filter(|frametime| self.is_on_target(**frametime))
+            .count();
+    }
+
+    fn is_on_target(&self, frametime: Duration) -> bool {
+        (frametime.as_secs_f64() - self.target.as_secs_f64()).abs()
+            <= self.target.as_secs_f64() * self.tolerance
+    }
+
+    /// Add a frame, dropping the frames that fall out of the window
+    pub fn push(&mut self, frametime: Duration) {
+        if let Some(last) = self.frametimes.back() {
+            self.add_delta(delta(*last, frametime), 1);
+        }
+        if self.is_on_target(frametime) {
+            self.on_target += 1;
+        }
+        self.frametimes.push_back(frametime);
+        self.total += frametime;
+
+        match self.window {
+            Window::Frames(n) => {
+                while self.frametimes.len() > n {
+                    self.pop_oldest();
+                }
+            }
+            Window::Time(duration) => {
+                while self.total > duration && self.frametimes.len() > 1 {
+                    self.pop_oldest();
+                }
+            }
+            Window::All => (),
+        }
+    }
+
+    /// Add the frame of an event
+    ///
+    /// A frame after an idle gap starts the window over, the gap says nothing about pacing
+    pub fn push_event(&mut self, event: &FrameEvent) {
+        if event.after_idle {
+            self.clear();
+        } else {
+            self.push(event.frametime);
+        }
+    }
+
+    fn pop_oldest(&mut self) {
+        let Some(oldest) = self.frametimes.pop_front() else {
+            return;
+        };
+
+        if let Some(next) = self.frametimes.front() {
+            self.add_delta(delta(oldest, *next), -1);
+        }
+        if self.is_on_target(oldest) {
+            self.on_target -= 1;
+        }
+        self.total -= oldest;
+    }
+
+    const fn add_delta(&mut self, delta: i128, sign: i128) {
+        self.delta_sum += sign * delta;
+        self.delta_squares += sign * delta * delta;
+    }
+
+    /// Forget every frame
+    pub fn clear(&mut self) {
+        self.frametimes.clear();
+        self.total = Duration::ZERO;
+        self.delta_sum = 0;
+        self.delta_squares = 0;
+        self.on_target = 0;
+    }
+
+    /// The pacing of the frames in the window, `None` until there are two frames
+    #[must_use]
+    pub fn metrics(&self) -> Option<PacingMetrics> {
+        let frames = self.frametimes.len();
+        if frames < 2 {
+            return None;
+        }
+
+        let deltas = (frames - 1) as f64;
+        let mean_delta = self.delta_sum as f64 / deltas;
+        let mean_square = self.delta_squares as f64 / deltas;
+        let delta_variance = (mean_square - mean_delta * mean_delta).max(0.0) / 1e12;
+        let delta_rms = mean_square.sqrt();
+
+        let mean_frametime = self.total.as_nanos() as f64 / frames as f64;
+        let smoothness = if mean_frametime > 0.0 {
+            (1.0 - delta_rms / mean_frametime).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+
+        Some(PacingMetrics {
+            frames,
+            mean_frametime: Duration::from_nanos(mean_frametime as u64),
+            delta_variance,
+            delta_rms: Duration::from_nanos(delta_rms as u64),
+            on_target: self.on_target as f64 / frames as f64,
+            smoothness,
+        })
+    }
+}
+
+const fn delta(previous: Duration, current: Duration) -> i128 {
+    current.as_nanos() as i128 - previous.as_nanos() as i128
+}
+
+impl Extend<Duration> for PacingMeter {
+    fn extend<I: IntoIterator<Item = Duration>>(&mut self, iter: I) {
+        for frametime in iter {
+            self.push(frametime);
+        }
+    }
+}
+
+impl Extend<FrameEvent> for PacingMeter {
+    fn extend<I: IntoIterator<Item = FrameEvent>>(&mut self, iter: I) {
+        for event in iter {
+            self.push_event(&event);
+        }
+    }
+}
+
+impl FromIterator<Duration> for PacingMeter {
+    fn from_iter<I: IntoIterator<Item = Duration>>(iter: I) -> Self {
+        let mut meter = Self::default();
+        meter.extend(iter);
+        meter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::PacingMeter;
+    use crate::{stats::Window, test_util::event};
+
+    const TARGET: Duration = Duration::from_micros(16_667);
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn measures_deltas() {
+        let meter: PacingMeter = [ms(10), ms(20), ms(10), ms(20)].into_iter().collect();
+        let metrics = meter.metrics().unwrap();
+
+        assert_eq!(metrics.frames, 4);
+        assert_eq!(metrics.mean_frametime, ms(15));
+        assert_eq!(metrics.delta_rms, ms(10));
+        // deltas of +10, -10 and +10ms
+        assert!((metrics.delta_variance - (100.0 - 100.0 / 9.0)).abs() < 1e-6);
+        assert!((metrics.smoothness - (1.0 - 10.0 / 15.0)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn slides_like_a_fresh_meter() {
+        let frametimes: Vec<_> = (0..200_u64).map(|i| ms(10 + i * 7 % 13)).collect();
+
+        let mut meter = PacingMeter::new(TARGET, Window::Frames(10));
+        meter.extend(frametimes.iter().copied());
+
+        let mut fresh = PacingMeter::new(TARGET, Window::All);
+        fresh.extend(frametimes[190..].iter().copied());
+
+        let (sliding, fresh) = (meter.metrics().unwrap(), fresh.metrics().unwrap());
+        assert_eq!(sliding.frames, 10);
+        assert_eq!(sliding.delta_rms, fresh.delta_rms);
+        assert!((sliding.delta_variance - fresh.delta_variance).abs() < 1e-9);
+        assert!((sliding.on_target - fresh.on_target).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn recounts_on_a_new_target() {
+        let mut meter = PacingMeter::new(TARGET, Window::All);
+        meter.extend([TARGET, TARGET, TARGET * 2, TARGET * 2]);
+        assert!((meter.metrics().unwrap().on_target - 0.5).abs() < f64::EPSILON);
+
+        meter.set_target(TARGET * 2);
+        assert!((meter.metrics().unwrap().on_target - 0.5).abs() < f64::EPSILON);
+
+        meter.set_target(ms(25));
+        let meter = meter.tolerance(0.5);
+        assert!((meter.metrics().unwrap().on_target - 1.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn starts_over_after_idle() {
+        let mut meter = PacingMeter::default();
+        meter.extend([event(ms(8), false), event(ms(25), false)]);
+        meter.push_event(&event(ms(5000), true));
+        assert!(meter.metrics().is_none());
+
+        meter.extend([event(TARGET, false), event(TARGET, false)]);
+        assert!((meter.metrics().unwrap().smoothness - 1.0).abs() < f64::EPSILON);
+    }
+}
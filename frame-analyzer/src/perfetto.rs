@@ -0,0 +1,476 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufWriter, Read, Write},
+    path::Path,
+};
+
+use crate::{
+    Pid,
+    config::{AnalyzerConfig, ClockSource, clock_ns},
+    error::Result,
+    event::FrameEvent,
+    jank::JankConfig,
+    record::{self, Recording},
+    surface_metrics::SurfaceMetrics,
+};
+
+// arbitrary, but has to stay the same for every packet of the trace
+const SEQUENCE_ID: u64 = 0x4652_414d;
+
+// perfetto's `BuiltinClock`
+const CLOCK_REALTIME: u64 = 1;
+const CLOCK_MONOTONIC: u64 = 3;
+const CLOCK_BOOTTIME: u64 = 6;
+
+// `TracePacket`
+const PACKET_CLOCK_SNAPSHOT: u32 = 6;
+const PACKET_TIMESTAMP: u32 = 8;
+const PACKET_SEQUENCE_ID: u32 = 10;
+const PACKET_TRACK_EVENT: u32 = 11;
+const PACKET_SEQUENCE_FLAGS: u32 = 13;
+const PACKET_TIMESTAMP_CLOCK_ID: u32 = 58;
+const PACKET_TRACK_DESCRIPTOR: u32 = 60;
+const SEQ_INCREMENTAL_STATE_CLEARED: u64 = 1;
+
+// `TrackEvent`
+const EVENT_TYPE: u32 = 9;
+const EVENT_TRACK_UUID: u32 = 11;
+const EVENT_NAME: u32 = 23;
+const EVENT_DOUBLE_COUNTER_VALUE: u32 = 44;
+const TYPE_SLICE_BEGIN: u64 = 1;
+const TYPE_SLICE_END: u64 = 2;
+const TYPE_INSTANT: u64 = 3;
+const TYPE_COUNTER: u64 = 4;
+
+// `TrackDescriptor`, `ProcessDescriptor` and `ClockSnapshot`
+const TRACK_UUID: u32 = 1;
+const TRACK_NAME: u32 = 2;
+const TRACK_PROCESS: u32 = 3;
+const TRACK_PARENT_UUID: u32 = 5;
+const TRACK_COUNTER: u32 = 8;
+const PROCESS_PID: u32 = 1;
+const PROCESS_NAME: u32 = 6;
+const SNAPSHOT_CLOCK: u32 = 1;
+const CLOCK_ID: u32 = 1;
+const CLOCK_TIMESTAMP: u32 = 2;
+
+struct Track {
+    uuid: u64,
+    fps_uuid: u64,
+    metrics: SurfaceMetrics,
+}
+
+/// Writes frames as a native Perfetto trace, a stream of protobuf `TracePacket`s
+///
+/// Much smaller than [`crate::ChromeTrace`], and the frames keep the clock they were timestamped with:
+/// clock snapshots map it to `CLOCK_BOOTTIME` and `CLOCK_REALTIME`, so the frames line up with the tracks of
+/// a trace recorded on the device, e.g. the ones of `SurfaceFlinger`, once the two traces are merged (concatenated).
+///
+/// Every process is a process track with a track per surface, holding a slice per frame (`idle` for the frame ending an idle period)
+/// and the janks as instants, and an `fps` counter track per surface, averaged over the last second
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use frame_analyzer::{AnalyzerBuilder, PerfettoTrace, Script, SyntheticSource};
+///
+/// # fn main() {
+/// # try_main().unwrap();
+/// # }
+/// #
+/// # fn try_main() -> anyhow::Result<()> {
+/// let script = Script::new(1000, 0x1).frames(120, Duration::from_micros(16_667));
+/// let source = SyntheticSource::new().script(script);
+/// let mut analyzer = AnalyzerBuilder::new().source(source).build()?;
+/// analyzer.attach_app(1000)?;
+///
+/// let mut trace = PerfettoTrace::new(Vec::new());
+/// trace.snapshot_clocks()?;
+/// trace.process_name(1000, "com.example.game")?;
+/// while let Some(event) = analyzer.recv_event() {
+/// trace.push(&event)?;
+/// }
+///
+/// let trace = trace.finish()?;
+/// assert!(!trace.is_empty());
+/// # Ok(())
+/// # }
+/// ```
+///
+/// A recording, with the clock snapshot taken when it started
+///
+/// ```
+/// use frame_analyzer::{AnalyzerConfig, PerfettoTrace, Recording};
+///
+/// # fn main() {
+/// # let _ = try_main();
+/// # }
+/// #
+/// # fn try_main() -> anyhow::Result<()> {
+/// let mut recording = Recording::open("session.frec")?;
+/// let mut trace = PerfettoTrace::create("session.pftrace")?;
+/// trace.write_recording(&mut recording, &AnalyzerConfig::default())?;
+/// trace.finish()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct PerfettoTrace<W: Write> {
+    writer: W,
+    clock: ClockSource,
+    jank: JankConfig,
+    processes: HashSet<Pid>,
+    tracks: HashMap<(Pid, usize), Track>,
+    first: bool,
+    packet: Vec<u8>,
+}
+
+impl PerfettoTrace<BufWriter<File>> {
+    /// Write the trace into a new file
+    ///
+    /// # Errors
+    ///
+    /// `IOError` if the file can't be created
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write> PerfettoTrace<W> {
+    /// Write the trace into `writer`
+    #[must_use]
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            clock: ClockSource::default(),
+            jank: JankConfig::default(),
+            processes: HashSet::new(),
+            tracks: HashMap::new(),
+            first: true,
+            packet: Vec::new(),
+        }
+    }
+
+    /// The clock the frames are timestamped with, the one of the analyzer, [`ClockSource::Monotonic`] by default
+    #[must_use]
+    pub const fn clock(mut self, clock: ClockSource) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// The thresholds of the jank markers of the surfaces seen from now on, [`JankConfig::default`] by default
+    #[must_use]
+    pub const fn jank_config(mut self, config: JankConfig) -> Self {
+        self.jank = config;
+        self
+    }
+
+    /// Snapshot the clocks now, mapping the frame clock to `CLOCK_BOOTTIME` and `CLOCK_REALTIME`
+    ///
+    /// When tracing live, snapshot at the start and every now and then:
+    /// `CLOCK_MONOTONIC` stops while the device sleeps and `CLOCK_REALTIME` may be set at any time
+    ///
+    /// # Errors
+    ///
+    /// `IOError` if writing fails
+    pub fn snapshot_clocks(&mut self) -> Result<()> {
+        let clocks = [
+            (CLOCK_MONOTONIC, clock_ns(libc::CLOCK_MONOTONIC)),
+            (CLOCK_BOOTTIME, clock_ns(libc::CLOCK_BOOTTIME)),
+            (CLOCK_REALTIME, clock_ns(libc::CLOCK_REALTIME)),
+        ];
+        self.write_snapshot(&clocks)
+    }
+
+    fn write_snapshot(&mut self, clocks: &[(u64, u64)]) -> Result<()> {
+        let mut snapshot = Vec::new();
+        for (id, timestamp) in clocks {
+            let mut clock = Vec::new();
+            put_varint_field(&mut clock, CLOCK_ID, *id);
+            put_varint_field(&mut clock, CLOCK_TIMESTAMP, *timestamp);
+            put_bytes_field(&mut snapshot, SNAPSHOT_CLOCK, &clock);
+        }
+
+        put_bytes_field(&mut self.packet, PACKET_CLOCK_SNAPSHOT, &snapshot);
+        self.emit()
+    }
+
+    /// Name the process `pid` in the trace, e.g. with its package name
+    ///
+    /// # Errors
+    ///
+    /// `IOError` if writing fails
+    pub fn process_name(&mut self, pid: Pid, name: &str) -> Result<()> {
+        self.processes.insert(pid);
+        self.describe_process(pid, Some(name))
+    }
+
+    fn describe_process(&mut self, pid: Pid, name: Option<&str>) -> Result<()> {
+        let mut process = Vec::new();
+        put_varint_field(
+            &mut process,
+            PROCESS_PID,
+            u64::try_from(pid).unwrap_or_default(),
+        );
+        if let Some(name) = name {
+            put_bytes_field(&mut process, PROCESS_NAME, name.as_bytes());
+        }
+
+        let mut track = Vec::new();
+        put_varint_field(&mut track, TRACK_UUID, uuid("process", pid, 0));
+        put_bytes_field(&mut track, TRACK_PROCESS, &process);
+
+        put_bytes_field(&mut self.packet, PACKET_TRACK_DESCRIPTOR, &track);
+        self.emit()
+    }
+
+    fn describe_track(&mut self, pid: Pid, uuid: u64, name: &str, counter: bool) -> Result<()> {
+        let mut track = Vec::new();
+        put_varint_field(&mut track, TRACK_UUID, uuid);
+        put_bytes_field(&mut track, TRACK_NAME, name.as_bytes());
+        put_varint_field(&mut track, TRACK_PARENT_UUID, self::uuid("process", pid, 0));
+        if counter {
+            put_bytes_field(&mut track, TRACK_COUNTER, &[]);
+        }
+
+        put_bytes_field(&mut self.packet, PACKET_TRACK_DESCRIPTOR, &track);
+        self.emit()
+    }
+
+    /// Add a frame
+    ///
+    /// # Errors
+    ///
+    /// `IOError` if writing fails
+    pub fn push(&mut self, event: &FrameEvent) -> Result<()> {
+        let pid = event.pid;
+        let surface = event.surface;
+
+        if self.processes.insert(pid) {
+            self.describe_process(pid, None)?;
+        }
+        if !self.tracks.contains_key(&(pid, surface)) {
+            let track = Track {
+                uuid: uuid("surface", pid, surface),
+                fps_uuid: uuid("fps", pid, surface),
+                metrics: SurfaceMetrics::new(self.jank),
+            };
+            self.describe_track(pid, track.uuid, &format!("surface {surface:#x}"), false)?;
+            self.describe_track(pid, track.fps_uuid, &format!("fps {surface:#x}"), true)?;
+            self.tracks.insert((pid, surface), track);
+        }
+
+        let Some(track) = self.tracks.get_mut(&(pid, surface)) else {
+            return Ok(());
+        };
+        let metrics = track.metrics.push(event);
+        let jank = metrics.jank.and_then(|frame| frame.kind);
+        let fps = metrics.fps;
+        let (uuid, fps_uuid) = (track.uuid, track.fps_uuid);
+
+        let end = event.timestamp_ns;
+        let start = end.saturating_sub(event.frametime.as_nanos() as u64);
+        let name = if event.after_idle { "idle" } else { "frame" };
+
+        self.write_event(start, uuid, TYPE_SLICE_BEGIN, Some(name), None)?;
+        self.write_event(end, uuid, TYPE_SLICE_END, None, None)?;
+        if let Some(kind) = jank {
+            self.write_event(end, uuid, TYPE_INSTANT, Some(&format!("{kind:?}")), None)?;
+        }
+        if let Some(fps) = fps {
+            self.write_event(end, fps_uuid, TYPE_COUNTER, None, Some(fps))?;
+        }
+
+        Ok(())
+    }
+
+    fn write_event(
+        &mut self,
+        timestamp: u64,
+        track_uuid: u64,
+        kind: u64,
+        name: Option<&str>,
+        value: Option<f64>,
+    ) -> Result<()> {
+        let mut event = Vec::new();
+        put_varint_field(&mut event, EVENT_TYPE, kind);
+        put_varint_field(&mut event, EVENT_TRACK_UUID, track_uuid);
+        if let Some(name) = name {
+            put_bytes_field(&mut event, EVENT_NAME, name.as_bytes());
+        }
+        if let Some(value) = value {
+            put_tag(&mut event, EVENT_DOUBLE_COUNTER_VALUE, 1);
+            event.extend_from_slice(&value.to_le_bytes());
+        }
+
+        let clock = match self.clock {
+            ClockSource::Monotonic => CLOCK_MONOTONIC,
+            ClockSource::Boottime => CLOCK_BOOTTIME,
+        };
+        put_varint_field(&mut self.packet, PACKET_TIMESTAMP, timestamp);
+        put_varint_field(&mut self.packet, PACKET_TIMESTAMP_CLOCK_ID, clock);
+        put_bytes_field(&mut self.packet, PACKET_TRACK_EVENT, &event);
+        self.emit()
+    }
+
+    /// Add every frame of a recording, as analyzed by an [`crate::Analyzer`] configured with `config`
+    ///
+    /// The frames keep the clock of the recording, the clock snapshot taken when it started is added
+    /// and the recorded processes are named with their cmdlines
+    ///
+    /// # Errors
+    ///
+    /// `IOError` if reading or writing fails and `RecordingError` if the recording is damaged
+    pub fn write_recording<R: Read>(
+        &mut self,
+        recording: &mut Recording<R>,
+        config: &AnalyzerConfig,
+    ) -> Result<()> {
+        self.clock = recording.clock().unwrap_or(config.clock);
+        if let Some(clocks) = recording.get("clocks").map(parse_clocks) {
+            self.write_snapshot(&clocks)?;
+        }
+
+        self.name_recorded(recording)?;
+        record::analyze(recording, config, |event| self.push(event))?;
+        // processes attached later in the recording
+        self.name_recorded(recording)
+    }
+
+    fn name_recorded<R: Read>(&mut self, recording: &Recording<R>) -> Result<()> {
+        for (pid, cmdline) in recording.pids() {
+            if !self.processes.contains(&pid) && !cmdline.is_empty() {
+                self.process_name(pid, cmdline)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// End the trace, returning the writer
+    ///
+    /// # Errors
+    ///
+    /// `IOError` if writing fails
+    pub fn finish(mut self) -> Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn emit(&mut self) -> Result<()> {
+        put_varint_field(&mut self.packet, PACKET_SEQUENCE_ID, SEQUENCE_ID);
+        if self.first {
+            put_varint_field(
+                &mut self.packet,
+                PACKET_SEQUENCE_FLAGS,
+                SEQ_INCREMENTAL_STATE_CLEARED,
+            );
+            self.first = false;
+        }
+
+        // a trace is a `repeated TracePacket packet = 1`
+        let mut header = Vec::with_capacity(6);
+        put_tag(&mut header, 1, 2);
+        put_varint(&mut header, self.packet.len() as u64);
+
+        self.writer.write_all(&header)?;
+        let result = self.writer.write_all(&self.packet);
+        self.packet.clear();
+        Ok(result?)
+    }
+}
+
+// FNV-1a, stable across runs and Rust versions,
+// and unlikely to collide with the tracks of other producers in a merged trace
+fn uuid(kind: &str, pid: Pid, surface: usize) -> u64 {
+    const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+
+    [
+        b"frame-analyzer\0".as_slice(),
+        kind.as_bytes(),
+        b"\0",
+        &pid.to_le_bytes(),
+        &(surface as u64).to_le_bytes(),
+    ]
+    .concat()
+    .iter()
+    .fold(OFFSET, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(PRIME)
+    })
+}
+
+// `monotonic:<ns> boottime:<ns> realtime:<ns>` of the recording header
+fn parse_clocks(value: &str) -> Vec<(u64, u64)> {
+    value
+        .split_whitespace()
+        .filter_map(|reading| {
+            let (name, timestamp) = reading.split_once(':')?;
+            let id = match name {
+                "monotonic" => CLOCK_MONOTONIC,
+                "boottime" => CLOCK_BOOTTIME,
+                "realtime" => CLOCK_REALTIME,
+                _ => return None,
+            };
+            Some((id, timestamp.parse().ok()?))
+        })
+        .collect()
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn put_tag(out: &mut Vec<u8>, field: u32, wire_type: u32) {
+    put_varint(out, u64::from(field << 3 | wire_type));
+}
+
+fn put_varint_field(out: &mut Vec<u8>, field: u32, value: u64) {
+    put_tag(out, field, 0);
+    put_varint(out, value);
+}
+
+fn put_bytes_field(out: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    put_tag(out, field, 2);
+    put_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::uuid;
+
+    #[test]
+    fn keeps_track_uuids_stable() {
+        // FNV-1a of "frame-analyzer\0surface\0", the pid and the surface
+        assert_eq!(uuid("surface", 1000, 0xb400_007a), 0x057c_8ead_89ab_24eb);
+
+        assert_ne!(uuid("surface", 1000, 0x1), uuid("fps", 1000, 0x1));
+        assert_ne!(uuid("surface", 1000, 0x1), uuid("surface", 1001, 0x1));
+        assert_ne!(uuid("process", 1000, 0), uuid("process", 1001, 0));
+    }
+}
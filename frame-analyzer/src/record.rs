@@ -0,0 +1,713 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{
+    collections::HashMap,
+    ffi::CStr,
+    fs::{self, File},
+    io::{self, BufReader, ErrorKind, Read, Write},
+    path::Path,
+    process::Command,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    Pid,
+    analyze_target::AnalyzeTarget,
+    config::{AnalyzerConfig, ClockSource, clock_ns},
+    error::{AnalyzerError, Result},
+    event::{FrameEvent, RawFrame},
+};
+
+const MAGIC: &[u8; 8] = b"FRAMEREC";
+const VERSION: u8 = 1;
+const METADATA: u8 = b'M';
+const FRAMES: u8 = b'F';
+// a chunk is written once it holds this many frames or is this old, whichever comes first
+const CHUNK_FRAMES: usize = 512;
+const CHUNK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Streams raw frames into a recording
+///
+/// Stops writing at the first error, which is returned by [`Recorder::finish`]
+pub struct Recorder {
+    writer: Box<dyn Write + Send>,
+    chunk: Vec<u8>,
+    frames: usize,
+    previous: RawFrame,
+    since: Instant,
+    error: Option<io::Error>,
+}
+
+impl Recorder {
+    /// Write the header of a recording made with `config`, with the processes in `pids` attached
+    pub fn new(
+        mut writer: Box<dyn Write + Send>,
+        config: &AnalyzerConfig,
+        pids: impl IntoIterator<Item = Pid>,
+    ) -> io::Result<Self> {
+        let mut metadata = vec![
+            (
+                "recorder".to_string(),
+                concat!("frame-analyzer ", env!("CARGO_PKG_VERSION")).to_string(),
+            ),
+            ("clock".to_string(), clock_name(config.clock).to_string()),
+            (
+                "started_ns".to_string(),
+                crate::config::now_ns(config.clock).to_string(),
+            ),
+            ("clocks".to_string(), clocks_metadata()),
+        ];
+        metadata.extend(system_metadata());
+        metadata.extend(config.probes.iter().map(|probe| {
+            (
+                "probe".to_string(),
+                format!("{}:{}", probe.library, probe.symbol),
+            )
+        }));
+        metadata.extend(pids.into_iter().map(pid_metadata));
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        write_chunk(&mut writer, METADATA, &encode_metadata(&metadata))?;
+        writer.flush()?;
+
+        Ok(Self {
+            writer,
+            chunk: Vec::new(),
+            frames: 0,
+            previous: RawFrame::default(),
+            since: Instant::now(),
+            error: None,
+        })
+    }
+
+    /// Add a frame to the current chunk, writing the chunk if it's due
+    pub fn record(&mut self, frame: &RawFrame) {
+        write_zigzag(
+            &mut self.chunk,
+            i64::from(frame.pid) - i64::from(self.previous.pid),
+        );
+        write_zigzag(
+            &mut self.chunk,
+            frame.ktime_ns.wrapping_sub(self.previous.ktime_ns) as i64,
+        );
+        write_zigzag(
+            &mut self.chunk,
+            frame.buffer.wrapping_sub(self.previous.buffer) as i64,
+        );
+        self.previous = *frame;
+        self.frames += 1;
+
+        if self.frames >= CHUNK_FRAMES {
+            self.flush();
+        }
+    }
+
+    /// Write a metadata chunk for a process attached after the recording started
+    pub fn attach(&mut self, pid: Pid) {
+        // keep the frames before the attach in order
+        self.flush();
+        self.write(METADATA, &encode_metadata(&[pid_metadata(pid)]));
+    }
+
+    /// Write the current chunk if it's older than the chunk interval
+    pub fn flush_if_due(&mut self) {
+        if self.frames > 0 && self.since.elapsed() >= CHUNK_INTERVAL {
+            self.flush();
+        }
+    }
+
+    /// How long until the current chunk is due, `None` if it's empty
+    pub fn wait_hint(&self) -> Option<Duration> {
+        (self.frames > 0).then(|| CHUNK_INTERVAL.saturating_sub(self.since.elapsed()))
+    }
+
+    /// Write the current chunk
+    fn flush(&mut self) {
+        if self.frames > 0 {
+            let mut payload = Vec::with_capacity(self.chunk.len() + 2);
+            write_varint(&mut payload, self.frames as u64);
+            payload.append(&mut self.chunk);
+            self.write(FRAMES, &payload);
+        }
+
+        self.frames = 0;
+        self.previous = RawFrame::default();
+        self.since = Instant::now();
+    }
+
+    fn write(&mut self, kind: u8, payload: &[u8]) {
+        if self.error.is_some() {
+            return;
+        }
+
+        if let Err(e) =
+            write_chunk(&mut self.writer, kind, payload).and_then(|()| self.writer.flush())
+        {
+            self.error = Some(e);
+        }
+    }
+
+    /// Write the current chunk and end the recording
+    pub fn finish(mut self) -> io::Result<()> {
+        self.flush();
+        self.error.take().map_or(Ok(()), Err)
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// A recording made by [`crate::Analyzer::record`], read frame by frame
+///
+/// Iterates over the recorded frames in the order they came out of the rings.
+/// A recording cut short, e.g. by a crash, ends with its last complete chunk
+///
+/// # Format
+///
+/// Every integer is a LEB128 varint unless noted
+///
+/// ```text
+/// file     = "FRAMEREC" version:u8 metadata chunk*
+/// chunk    = kind:u8 len:varint payload[len] crc32(payload):u32le
+/// metadata = kind 'M', payload = count:varint (key:str value:str)*
+/// frames   = kind 'F', payload = count:varint (pid:zigzag ktime_ns:zigzag buffer:zigzag)*
+/// str      = len:varint utf8[len]
+/// ```
+///
+/// Frames are delta encoded against the previous frame of the same chunk, so every chunk decodes on its own.
+/// Chunks of unknown kinds are skipped
+///
+/// # Examples
+///
+/// ```
+/// # use frame_analyzer::Recording;
+/// #
+/// # fn main() {
+/// # let _ = try_main();
+/// # }
+/// #
+/// # fn try_main() -> anyhow::Result<()> {
+/// let recording = Recording::open("session.frec")?;
+/// println!("kernel: {:?}", recording.get("kernel"));
+///
+/// for frame in recording {
+/// let frame = frame?;
+/// println!("process: {}, surface: {:#x}, at {}ns", frame.pid, frame.buffer, frame.ktime_ns);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct Recording<R = BufReader<File>> {
+    reader: R,
+    metadata: Vec<(String, String)>,
+    frames: Vec<RawFrame>,
+    ended: bool,
+}
+
+impl Recording {
+    /// Open a recording file
+    ///
+    /// # Errors
+    ///
+    /// `IOError` if the file can't be read and `RecordingError` if it isn't a recording
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> Recording<R> {
+    /// Read a recording from `reader`
+    ///
+    /// # Errors
+    ///
+    /// `IOError` if reading fails and `RecordingError` if it isn't a recording
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut magic = [0; 9];
+        reader
+            .read_exact(&mut magic)
+            .map_err(|_| recording_error("not a recording"))?;
+        if &magic[..8] != MAGIC {
+            return Err(recording_error("not a recording"));
+        }
+        if magic[8] != VERSION {
+            return Err(recording_error(format!("unsupported version {}", magic[8])));
+        }
+
+        let mut recording = Self {
+            reader,
+            metadata: Vec::new(),
+            frames: Vec::new(),
+            ended: false,
+        };
+        match recording.read_chunk()? {
+            Some(METADATA) => Ok(recording),
+            _ => Err(recording_error("missing header")),
+        }
+    }
+
+    /// Every key and value of the metadata read so far, in order
+    ///
+    /// The header holds `recorder`, `clock`, `started_ns`, `clocks`, `kernel`, `machine`, the Android `ro.*` properties,
+    /// a `probe` per probe target and a `pid` per attached process as `"<pid> <cmdline>"`.
+    /// A `pid` is added whenever a process is attached later in the recording
+    #[must_use]
+    pub fn metadata(&self) -> &[(String, String)] {
+        &self.metadata
+    }
+
+    /// The first value of `key` in the metadata
+    #[must_use]
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.metadata
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The clock the frames were timestamped with
+    #[must_use]
+    pub fn clock(&self) -> Option<ClockSource> {
+        match self.get("clock")? {
+            "monotonic" => Some(ClockSource::Monotonic),
+            "boottime" => Some(ClockSource::Boottime),
+            _ => None,
+        }
+    }
+
+    /// The processes attached so far with their cmdlines
+    #[must_use]
+    pub fn pids(&self) -> Vec<(Pid, &str)> {
+        self.metadata
+            .iter()
+            .filter(|(key, _)| key == "pid")
+            .filter_map(|(_, value)| {
+                let (pid, cmdline) = value.split_once(' ').unwrap_or((value, ""));
+                Some((pid.parse().ok()?, cmdline))
+            })
+            .collect()
+    }
+
+    /// The next recorded frame, `None` at the end of the recording
+    ///
+    /// # Errors
+    ///
+    /// `IOError` if reading fails and `RecordingError` if a chunk is damaged
+    pub fn next_frame(&mut self) -> Result<Option<RawFrame>> {
+        while self.frames.is_empty() {
+            if self.read_chunk()?.is_none() {
+                return Ok(None);
+            }
+        }
+
+        Ok(self.frames.pop())
+    }
+
+    fn read_chunk(&mut self) -> Result<Option<u8>> {
+        if self.ended {
+            return Ok(None);
+        }
+
+        let chunk = read_chunk(&mut self.reader);
+        let Some((kind, payload)) = chunk? else {
+            self.ended = true;
+            return Ok(None);
+        };
+
+        let mut payload = payload.as_slice();
+        match kind {
+            METADATA => {
+                let count = read_varint(&mut payload)?;
+                for _ in 0..count {
+                    let key = read_str(&mut payload)?;
+                    let value = read_str(&mut payload)?;
+                    self.metadata.push((key, value));
+                }
+            }
+            FRAMES => {
+                let count = read_varint(&mut payload)?;
+                let mut previous = RawFrame::default();
+                // every frame takes at least a byte, don't trust the count beyond that
+                let capacity =
+                    usize::try_from(count).map_or(payload.len(), |count| count.min(payload.len()));
+                let mut frames = Vec::with_capacity(capacity);
+                for _ in 0..count {
+                    let frame = RawFrame {
+                        pid: (i64::from(previous.pid) + read_zigzag(&mut payload)?) as Pid,
+                        ktime_ns: previous
+                            .ktime_ns
+                            .wrapping_add(read_zigzag(&mut payload)? as u64),
+                        buffer: previous
+                            .buffer
+                            .wrapping_add(read_zigzag(&mut payload)? as usize),
+                    };
+                    frames.push(frame);
+                    previous = frame;
+                }
+
+                // popped from the back
+                frames.reverse();
+                self.frames = frames;
+            }
+            // chunks of later versions are skipped
+            _ => (),
+        }
+
+        Ok(Some(kind))
+    }
+}
+
+impl<R: Read> Iterator for Recording<R> {
+    type Item = Result<RawFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().transpose()
+    }
+}
+
+/// Run every frame of `recording` through the analysis of an analyzer configured with `config`
+pub fn analyze<R: Read>(
+    recording: &mut Recording<R>,
+    config: &AnalyzerConfig,
+    mut f: impl FnMut(&FrameEvent) -> Result<()>,
+) -> Result<()> {
+    let mut targets: HashMap<Pid, AnalyzeTarget> = HashMap::new();
+
+    while let Some(frame) = recording.next_frame()? {
+        let target = targets
+            .entry(frame.pid)
+            .or_insert_with(|| AnalyzeTarget::new(frame.pid, config));
+        if let Some(event) = target.process(&frame) {
+            f(&event)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn recording_error(message: impl Into<String>) -> AnalyzerError {
+    AnalyzerError::RecordingError(message.into())
+}
+
+const fn clock_name(clock: ClockSource) -> &'static str {
+    match clock {
+        ClockSource::Monotonic => "monotonic",
+        ClockSource::Boottime => "boottime",
+    }
+}
+
+// readings of the clocks taken together, to line the recorded timestamps up with other traces
+fn clocks_metadata() -> String {
+    format!(
+        "monotonic:{} boottime:{} realtime:{}",
+        clock_ns(libc::CLOCK_MONOTONIC),
+        clock_ns(libc::CLOCK_BOOTTIME),
+        clock_ns(libc::CLOCK_REALTIME)
+    )
+}
+
+fn pid_metadata(pid: Pid) -> (String, String) {
+    let cmdline = fs::read(format!("/proc/{pid}/cmdline")).unwrap_or_default();
+    let cmdline = String::from_utf8_lossy(&cmdline).replace('\0', " ");
+    ("pid".to_string(), format!("{pid} {}", cmdline.trim()))
+}
+
+fn system_metadata() -> Vec<(String, String)> {
+    let mut metadata = Vec::new();
+
+    let mut name = unsafe { std::mem::zeroed::<libc::utsname>() };
+    if unsafe { libc::uname(&raw mut name) } == 0 {
+        let field = |field: &[libc::c_char]| {
+            unsafe { CStr::from_ptr(field.as_ptr()) }
+                .to_string_lossy()
+                .into_owned()
+        };
+        metadata.push(("kernel".to_string(), field(&name.release)));
+        metadata.push(("machine".to_string(), field(&name.machine)));
+    }
+
+    // only there on Android
+    for property in [
+        "ro.product.manufacturer",
+        "ro.product.model",
+        "ro.build.version.release",
+        "ro.build.version.sdk",
+        "ro.build.fingerprint",
+    ] {
+        let Ok(output) = Command::new("getprop").arg(property).output() else {
+            break;
+        };
+        let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if !value.is_empty() {
+            metadata.push((property.to_string(), value));
+        }
+    }
+
+    metadata
+}
+
+fn encode_metadata(metadata: &[(String, String)]) -> Vec<u8> {
+    let mut payload = Vec::new();
+    write_varint(&mut payload, metadata.len() as u64);
+    for (key, value) in metadata {
+        write_str(&mut payload, key);
+        write_str(&mut payload, value);
+    }
+    payload
+}
+
+fn write_chunk(writer: &mut impl Write, kind: u8, payload: &[u8]) -> io::Result<()> {
+    let mut header = vec![kind];
+    write_varint(&mut header, payload.len() as u64);
+
+    writer.write_all(&header)?;
+    writer.write_all(payload)?;
+    writer.write_all(&crc32(payload).to_le_bytes())
+}
+
+/// `None` at the end of the recording, including a chunk cut short
+fn read_chunk(reader: &mut impl Read) -> Result<Option<(u8, Vec<u8>)>> {
+    let mut kind = [0];
+    match reader.read(&mut kind) {
+        Ok(0) => return Ok(None),
+        Ok(_) => (),
+        Err(e) => return Err(e.into()),
+    }
+
+    let result = (|| {
+        let len = read_varint_from(reader)?;
+        // read no more than there is, a damaged length mustn't allocate its worth up front
+        let mut payload = Vec::new();
+        reader.by_ref().take(len).read_to_end(&mut payload)?;
+        if (payload.len() as u64) < len {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        let mut crc = [0; 4];
+        reader.read_exact(&mut crc)?;
+        Ok::<_, io::Error>((payload, u32::from_le_bytes(crc)))
+    })();
+
+    match result {
+        Ok((payload, crc)) if crc == crc32(&payload) => Ok(Some((kind[0], payload))),
+        Ok(_) => Err(recording_error("damaged chunk")),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+const fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+const fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+fn write_zigzag(buf: &mut Vec<u8>, value: i64) {
+    write_varint(buf, zigzag(value));
+}
+
+fn write_str(buf: &mut Vec<u8>, value: &str) {
+    write_varint(buf, value.len() as u64);
+    buf.extend_from_slice(value.as_bytes());
+}
+
+fn read_varint_from(reader: &mut impl Read) -> io::Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        reader.read_exact(&mut byte)?;
+        value |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(ErrorKind::InvalidData.into())
+}
+
+fn read_varint(payload: &mut &[u8]) -> Result<u64> {
+    read_varint_from(payload).map_err(|_| recording_error("damaged chunk"))
+}
+
+fn read_zigzag(payload: &mut &[u8]) -> Result<i64> {
+    read_varint(payload).map(unzigzag)
+}
+
+fn read_str(payload: &mut &[u8]) -> Result<String> {
+    let len = usize::try_from(read_varint(payload)?).unwrap_or(usize::MAX);
+    if len > payload.len() {
+        return Err(recording_error("damaged chunk"));
+    }
+
+    let (value, rest) = payload.split_at(len);
+    *payload = rest;
+    String::from_utf8(value.to_vec()).map_err(|_| recording_error("damaged chunk"))
+}
+
+/// CRC-32 (IEEE)
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & 0u32.wrapping_sub(crc & 1));
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, time::Duration};
+
+    use super::{CHUNK_FRAMES, FRAMES, MAGIC, Recorder, Recording, VERSION, analyze, write_varint};
+    use crate::{
+        AnalyzerError,
+        config::{AnalyzerConfig, SurfaceSelection},
+        event::RawFrame,
+        test_util::Buffer,
+    };
+
+    fn frames(count: usize) -> Vec<RawFrame> {
+        (0..count)
+            .map(|i| RawFrame {
+                pid: [100, 200][i % 2],
+                ktime_ns: 1_000_000_000 + i as u64 * 16_666_667,
+                buffer: 0xb400_0000 + i % 3,
+            })
+            .collect()
+    }
+
+    // a whole chunk of frames followed by a smaller one
+    fn record(frames: &[RawFrame]) -> Vec<u8> {
+        let buffer = Buffer::default();
+        let mut recorder =
+            Recorder::new(Box::new(buffer.clone()), &AnalyzerConfig::default(), [100]).unwrap();
+        for frame in frames {
+            recorder.record(frame);
+        }
+        recorder.attach(200);
+        recorder.finish().unwrap();
+
+        buffer.take()
+    }
+
+    fn read(bytes: &[u8]) -> Result<Vec<RawFrame>, AnalyzerError> {
+        Recording::new(bytes)?.collect()
+    }
+
+    #[test]
+    fn round_trips() {
+        let frames = frames(CHUNK_FRAMES + 10);
+        let bytes = record(&frames);
+
+        let mut recording = Recording::new(bytes.as_slice()).unwrap();
+        assert_eq!(recording.clock(), Some(crate::ClockSource::Monotonic));
+        assert!(
+            recording
+                .get("machine")
+                .is_some_and(|machine| !machine.is_empty())
+        );
+        assert_eq!(
+            recording.by_ref().collect::<Result<Vec<_>, _>>().unwrap(),
+            frames
+        );
+        let pids: Vec<_> = recording.pids().into_iter().map(|(pid, _)| pid).collect();
+        assert_eq!(pids, [100, 200]);
+    }
+
+    #[test]
+    fn rejects_damaged_chunks() {
+        let mut bytes = record(&frames(CHUNK_FRAMES + 10));
+        // within the first chunk of frames
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 0xff;
+
+        assert!(matches!(
+            read(&bytes),
+            Err(AnalyzerError::RecordingError(_))
+        ));
+    }
+
+    #[test]
+    fn ends_at_a_torn_tail() {
+        let frames = frames(CHUNK_FRAMES + 10);
+        let bytes = record(&frames);
+
+        // through the metadata of the attach and the last chunk of frames
+        for cut in 1..64 {
+            let read = read(&bytes[..bytes.len() - cut]).unwrap();
+            assert!([CHUNK_FRAMES, frames.len()].contains(&read.len()), "{cut}");
+            assert_eq!(read, frames[..read.len()], "{cut}");
+        }
+    }
+
+    #[test]
+    fn ends_at_a_chunk_longer_than_the_recording() {
+        let mut bytes = record(&[]);
+        bytes.push(FRAMES);
+        write_varint(&mut bytes, u64::MAX >> 1);
+        bytes.extend_from_slice(&[1, 2, 3]);
+
+        assert_eq!(read(&bytes).unwrap(), []);
+    }
+
+    #[test]
+    fn analyzes_every_process() {
+        let frames = frames(CHUNK_FRAMES + 10);
+        let mut recording = Recording::new(Cursor::new(record(&frames))).unwrap();
+
+        let config = AnalyzerConfig {
+            surface_selection: SurfaceSelection::All,
+            ..AnalyzerConfig::default()
+        };
+        let mut events = Vec::new();
+        analyze(&mut recording, &config, |event| {
+            events.push(*event);
+            Ok(())
+        })
+        .unwrap();
+
+        // every process starts with a frame on each of its 3 surfaces
+        assert_eq!(events.len(), frames.len() - 6);
+        assert!(
+            events
+                .iter()
+                .all(|event| event.frametime == Duration::from_nanos(6 * 16_666_667))
+        );
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(Recording::new(&b"FRAMERE"[..]).is_err());
+        assert!(Recording::new(&[*MAGIC, [VERSION + 1; 8]].concat()[..9]).is_err());
+    }
+}
@@ -0,0 +1,306 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{Pid, error::Result, event::RawFrame, record::Recording, source::FrameSource};
+
+// frames handed out by one read when replaying as fast as possible
+const FAST_BATCH: usize = 1024;
+
+/// How fast a [`ReplaySource`] plays a recording back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pace {
+    /// Every frame as soon as it's read
+    Fast,
+    /// Every frame as long after the first one as it was recorded
+    RealTime,
+}
+
+/// Frames of a [`Recording`], played back through an [`crate::Analyzer`]
+///
+/// Needs neither root nor an Android device, the analysis runs exactly as on the recorded frames.
+/// The analyzer gets the frames of the recorded processes it's attached to, see [`Recording::pids`].
+/// Receiving may return `None` before the recording ends, check [`crate::Analyzer::ended`] and [`crate::Analyzer::take_error`]
+///
+/// # Examples
+///
+/// ```
+/// # use frame_analyzer::{AnalyzerBuilder, Pace, ReplaySource};
+/// #
+/// # fn main() {
+/// # let _ = try_main();
+/// # }
+/// #
+/// # fn try_main() -> anyhow::Result<()> {
+/// let source = ReplaySource::open("session.frec", Pace::Fast)?;
+/// let pids: Vec<_> = source.recording().pids().iter().map(|(pid, _)| *pid).collect();
+///
+/// let mut analyzer = AnalyzerBuilder::new().source(source).build()?;
+/// for pid in pids {
+/// analyzer.attach_app(pid)?;
+/// }
+///
+/// while !analyzer.ended() {
+/// if let Some((pid, frametime)) = analyzer.recv() {
+/// println!("process: {pid}, frametime: {frametime:?}");
+/// }
+/// if let Some(e) = analyzer.take_error() {
+/// return Err(e.into());
+/// }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct ReplaySource<R = BufReader<File>> {
+    recording: Recording<R>,
+    pace: Pace,
+    attached: HashSet<Pid>,
+    next: Option<RawFrame>,
+    // when and at which timestamp the first frame was played
+    start: Option<(Instant, u64)>,
+    last_ns: u64,
+    ended: bool,
+}
+
+impl ReplaySource {
+    /// Play the recording in a file back
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Recording::open`]
+    pub fn open(path: impl AsRef<Path>, pace: Pace) -> Result<Self> {
+        Ok(Self::new(Recording::open(path)?, pace))
+    }
+}
+
+impl<R: Read> ReplaySource<R> {
+    /// Play `recording` back
+    #[must_use]
+    pub fn new(recording: Recording<R>, pace: Pace) -> Self {
+        Self {
+            recording,
+            pace,
+            attached: HashSet::new(),
+            next: None,
+            start: None,
+            last_ns: 0,
+            ended: false,
+        }
+    }
+
+    /// The recording played back, with the metadata read so far
+    #[must_use]
+    pub const fn recording(&self) -> &Recording<R> {
+        &self.recording
+    }
+
+    /// Whether every frame has been played
+    #[must_use]
+    pub const fn ended(&self) -> bool {
+        self.ended
+    }
+
+    fn peek(&mut self) -> Result<Option<RawFrame>> {
+        if self.next.is_none() && !self.ended {
+            self.next = self.recording.next_frame()?;
+            self.ended = self.next.is_none();
+        }
+
+        Ok(self.next)
+    }
+
+    fn play(&mut self, frames: &mut Vec<RawFrame>, frame: RawFrame) {
+        self.next = None;
+        self.last_ns = frame.ktime_ns;
+        if self.attached.contains(&frame.pid) {
+            frames.push(frame);
+        }
+    }
+
+    fn due(&mut self, frame: &RawFrame) -> Instant {
+        let (instant, ktime_ns) = *self
+            .start
+            .get_or_insert_with(|| (Instant::now(), frame.ktime_ns));
+        instant + Duration::from_nanos(frame.ktime_ns.saturating_sub(ktime_ns))
+    }
+
+    fn read_fast(&mut self, frames: &mut Vec<RawFrame>) -> Result<()> {
+        // frames of processes that aren't attached count as well, so a read never plays the whole recording
+        for _ in 0..FAST_BATCH {
+            let Some(frame) = self.peek()? else {
+                break;
+            };
+            self.play(frames, frame);
+        }
+
+        Ok(())
+    }
+
+    fn read_real_time(
+        &mut self,
+        frames: &mut Vec<RawFrame>,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            let len = frames.len();
+            while let Some(frame) = self.peek()? {
+                if self.due(&frame) > Instant::now() {
+                    break;
+                }
+                self.play(frames, frame);
+            }
+
+            let Some(next) = self.peek()? else {
+                return Ok(());
+            };
+            if frames.len() > len {
+                return Ok(());
+            }
+
+            let due = self.due(&next);
+            let wake = deadline.map_or(due, |deadline| due.min(deadline));
+            thread::sleep(wake.saturating_duration_since(Instant::now()));
+
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) && due > Instant::now() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<R: Read + Send> FrameSource for ReplaySource<R> {
+    fn attach(&mut self, pid: Pid) -> Result<()> {
+        self.attached.insert(pid);
+        Ok(())
+    }
+
+    fn detach(&mut self, pid: Pid) -> Result<()> {
+        self.attached.remove(&pid);
+        Ok(())
+    }
+
+    fn read(&mut self, frames: &mut Vec<RawFrame>, timeout: Option<Duration>) -> Result<()> {
+        match self.pace {
+            Pace::Fast => self.read_fast(frames),
+            Pace::RealTime => self.read_real_time(frames, timeout),
+        }
+    }
+
+    fn now_ns(&self) -> u64 {
+        match (self.pace, self.start) {
+            (Pace::RealTime, Some((instant, ktime_ns))) => {
+                ktime_ns + instant.elapsed().as_nanos() as u64
+            }
+            _ => self.last_ns,
+        }
+    }
+
+    fn ended(&self) -> bool {
+        self.ended
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{FAST_BATCH, Pace, ReplaySource};
+    use crate::{
+        AnalyzerBuilder, AnalyzerError, config::AnalyzerConfig, event::RawFrame, record::Recorder,
+        record::Recording, source::FrameSource, test_util::Buffer,
+    };
+
+    // frames of 100 for two batches, then a few of 200
+    fn record() -> Vec<u8> {
+        let buffer = Buffer::default();
+        let mut recorder = Recorder::new(
+            Box::new(buffer.clone()),
+            &AnalyzerConfig::default(),
+            [100, 200],
+        )
+        .unwrap();
+        for i in 0..FAST_BATCH * 2 + 10 {
+            recorder.record(&RawFrame {
+                pid: if i < FAST_BATCH * 2 { 100 } else { 200 },
+                ktime_ns: i as u64 * 16_666_667,
+                buffer: 1,
+            });
+        }
+        recorder.finish().unwrap();
+
+        buffer.take()
+    }
+
+    #[test]
+    fn plays_a_batch_at_a_time() {
+        let recording = Recording::new(Cursor::new(record())).unwrap();
+        let mut source = ReplaySource::new(recording, Pace::Fast);
+        source.attach(200).unwrap();
+
+        let mut frames = Vec::new();
+        for _ in 0..2 {
+            source.read(&mut frames, None).unwrap();
+            assert!(frames.is_empty());
+            assert!(!FrameSource::ended(&source));
+        }
+
+        source.read(&mut frames, None).unwrap();
+        assert_eq!(frames.len(), 10);
+        source.read(&mut frames, None).unwrap();
+        assert!(FrameSource::ended(&source));
+    }
+
+    #[test]
+    fn reports_the_end_and_errors() {
+        let recording = Recording::new(Cursor::new(record())).unwrap();
+        let source = ReplaySource::new(recording, Pace::Fast);
+        let mut analyzer = AnalyzerBuilder::new().source(source).build().unwrap();
+        analyzer.attach_app(200).unwrap();
+
+        let mut frames = 0;
+        while !analyzer.ended() {
+            frames += usize::from(analyzer.recv().is_some());
+        }
+        assert_eq!(frames, 9);
+        assert!(analyzer.take_error().is_none());
+
+        let mut damaged = record();
+        let middle = damaged.len() / 2;
+        damaged[middle] ^= 0xff;
+        let recording = Recording::new(Cursor::new(damaged)).unwrap();
+        let source = ReplaySource::new(recording, Pace::Fast);
+        let mut analyzer = AnalyzerBuilder::new().source(source).build().unwrap();
+        analyzer.attach_app(100).unwrap();
+
+        while analyzer.recv().is_some() {}
+        assert!(matches!(
+            analyzer.take_error(),
+            Some(AnalyzerError::RecordingError(_))
+        ));
+    }
+}
@@ -0,0 +1,607 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc, Condvar, Mutex, MutexGuard, PoisonError, Weak,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{Analyzer, FrameEvent, Pid, error::Result};
+
+// How long the reader thread holds the analyzer while waiting for frames
+const READ_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Which frames a [`Subscription`] receives
+///
+/// # Examples
+///
+/// ```
+/// use frame_analyzer::Filter;
+///
+/// let everything = Filter::all();
+/// let one_surface = Filter::all().pid(1234).surface(0x7f00_1234);
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Filter {
+    pid: Option<Pid>,
+    surface: Option<usize>,
+}
+
+impl Filter {
+    /// Frames of all attached apps
+    #[must_use]
+    pub const fn all() -> Self {
+        Self {
+            pid: None,
+            surface: None,
+        }
+    }
+
+    /// Only frames rendered by `pid`
+    #[must_use]
+    pub const fn pid(mut self, pid: Pid) -> Self {
+        self.pid = Some(pid);
+        self
+    }
+
+    /// Only frames queued by `surface`, see [`FrameEvent::surface`]
+    #[must_use]
+    pub const fn surface(mut self, surface: usize) -> Self {
+        self.surface = Some(surface);
+        self
+    }
+
+    /// Whether `event` passes the filter
+    #[must_use]
+    pub fn matches(&self, event: &FrameEvent) -> bool {
+        self.pid.is_none_or(|pid| pid == event.pid)
+            && self.surface.is_none_or(|surface| surface == event.surface)
+    }
+}
+
+/// What happens when a [`Subscription`] doesn't keep up with the frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Keep at most this many frames, the oldest ones are dropped
+    DropOldest(usize),
+    /// Keep at most this many frames, the reader thread waits for the subscriber once it's full.
+    /// Note this holds back every other subscriber as well
+    Block(usize),
+    /// Keep only the latest frame of every surface
+    Coalesce,
+}
+
+#[derive(Default)]
+struct Queue {
+    events: VecDeque<FrameEvent>,
+    dropped: u64,
+    // the subscription went away
+    closed: bool,
+    // the reader thread went away
+    disconnected: bool,
+}
+
+struct Channel {
+    filter: Filter,
+    policy: Backpressure,
+    queue: Mutex<Queue>,
+    readable: Condvar,
+    writable: Condvar,
+}
+
+impl Channel {
+    fn queue(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn push(&self, event: FrameEvent, running: &AtomicBool) {
+        let mut queue = self.queue();
+
+        match self.policy {
+            Backpressure::DropOldest(capacity) => {
+                if queue.events.len() >= capacity.max(1) {
+                    queue.events.pop_front();
+                    queue.dropped += 1;
+                }
+            }
+            Backpressure::Block(capacity) => {
+                while queue.events.len() >= capacity.max(1)
+                    && !queue.closed
+                    && running.load(Ordering::Acquire)
+                {
+                    queue = self
+                        .writable
+                        .wait_timeout(queue, READ_INTERVAL)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0;
+                }
+            }
+            Backpressure::Coalesce => {
+                let stale = queue
+                    .events
+                    .iter()
+                    .position(|queued| queued.pid == event.pid && queued.surface == event.surface);
+                if let Some(stale) = stale {
+                    queue.events.remove(stale);
+                    queue.dropped += 1;
+                }
+            }
+        }
+
+        if queue.closed {
+            return;
+        }
+
+        queue.events.push_back(event);
+        drop(queue);
+        self.readable.notify_one();
+    }
+
+    fn disconnect(&self) {
+        self.queue().disconnected = true;
+        self.readable.notify_all();
+    }
+}
+
+/// A receiver of the frames of a [`SharedAnalyzer`], see [`SharedAnalyzer::subscribe`]
+///
+/// Unsubscribes when dropped
+pub struct Subscription {
+    channel: Arc<Channel>,
+}
+
+impl Subscription {
+    /// Waits for the next frame, `None` once the [`SharedAnalyzer`] is gone and every queued frame is received
+    pub fn recv(&self) -> Option<FrameEvent> {
+        let mut queue = self.channel.queue();
+
+        loop {
+            if let Some(event) = queue.events.pop_front() {
+                self.channel.writable.notify_one();
+                return Some(event);
+            }
+
+            if queue.disconnected {
+                return None;
+            }
+
+            queue = self
+                .channel
+                .readable
+                .wait(queue)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Waits for the next frame, returning `None` if it waits more than timeout
+    pub fn recv_timeout(&self, time: Duration) -> Option<FrameEvent> {
+        let deadline = Instant::now() + time;
+        let mut queue = self.channel.queue();
+
+        loop {
+            if let Some(event) = queue.events.pop_front() {
+                self.channel.writable.notify_one();
+                return Some(event);
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if queue.disconnected || remaining.is_zero() {
+                return None;
+            }
+
+            queue = self
+                .channel
+                .readable
+                .wait_timeout(queue, remaining)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+
+    /// Receives a frame without blocking
+    #[must_use]
+    pub fn try_recv(&self) -> Option<FrameEvent> {
+        let event = self.channel.queue().events.pop_front();
+        if event.is_some() {
+            self.channel.writable.notify_one();
+        }
+
+        event
+    }
+
+    /// How many frames this subscription lost to its [`Backpressure`] policy
+    #[must_use]
+    pub fn dropped(&self) -> u64 {
+        self.channel.queue().dropped
+    }
+
+    /// An iterator waiting for frames, ends once the [`SharedAnalyzer`] is gone
+    pub fn iter(&self) -> impl Iterator<Item = FrameEvent> + '_ {
+        std::iter::from_fn(|| self.recv())
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.channel.queue().closed = true;
+        // the reader may be waiting for room in this subscription
+        self.channel.writable.notify_all();
+    }
+}
+
+type Callback = Box<dyn FnMut(&FrameEvent) + Send>;
+
+#[derive(Default)]
+struct Registry {
+    next_id: u64,
+    registered: Vec<(u64, Callback)>,
+    // unregistered while they were running
+    removed: Vec<u64>,
+}
+
+/// The callbacks of a [`SharedAnalyzer`], see [`SharedAnalyzer::on_frame`]
+#[derive(Default)]
+struct Callbacks(Arc<Mutex<Registry>>);
+
+impl Callbacks {
+    fn registry(&self) -> MutexGuard<'_, Registry> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn register(&self, callback: impl FnMut(&FrameEvent) + Send + 'static) -> CallbackGuard {
+        let mut registry = self.registry();
+        let id = registry.next_id;
+        registry.next_id += 1;
+        registry.registered.push((id, Box::new(callback)));
+        drop(registry);
+
+        CallbackGuard {
+            registry: Arc::downgrade(&self.0),
+            id,
+        }
+    }
+
+    /// Run every callback on `events`
+    fn run(&self, events: &[FrameEvent]) {
+        // run them unlocked, so callbacks can register and unregister callbacks
+        let mut running = std::mem::take(&mut self.registry().registered);
+        if running.is_empty() {
+            return;
+        }
+
+        for (_, callback) in &mut running {
+            for event in events {
+                callback(event);
+            }
+        }
+
+        let mut registry = self.registry();
+        let removed = std::mem::take(&mut registry.removed);
+        running.retain(|(id, _)| !removed.contains(id));
+        running.append(&mut registry.registered);
+        registry.registered = running;
+    }
+}
+
+/// Keeps a callback registered by [`SharedAnalyzer::on_frame`], unregisters it when dropped
+#[must_use = "the callback is unregistered right away if the guard is dropped"]
+pub struct CallbackGuard {
+    registry: Weak<Mutex<Registry>>,
+    id: u64,
+}
+
+impl CallbackGuard {
+    /// Unregister the callback, same as dropping the guard
+    pub fn unregister(self) {}
+}
+
+impl Drop for CallbackGuard {
+    fn drop(&mut self) {
+        let Some(registry) = self.registry.upgrade() else {
+            return;
+        };
+
+        let mut registry = registry.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(index) = registry
+            .registered
+            .iter()
+            .position(|(id, _)| *id == self.id)
+        {
+            let callback = registry.registered.remove(index);
+            // whatever the callback captured is dropped unlocked
+            drop(registry);
+            drop(callback);
+        } else {
+            registry.removed.push(self.id);
+        }
+    }
+}
+
+struct Inner {
+    analyzer: Mutex<Analyzer>,
+    channels: Mutex<Vec<Weak<Channel>>>,
+    callbacks: Callbacks,
+    running: AtomicBool,
+}
+
+impl Inner {
+    fn analyzer(&self) -> MutexGuard<'_, Analyzer> {
+        self.analyzer.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn channels(&self) -> MutexGuard<'_, Vec<Weak<Channel>>> {
+        self.channels.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// Tells the subscribers the reader thread is gone, also when it unwinds
+struct Disconnect<'a>(&'a Inner);
+
+impl Drop for Disconnect<'_> {
+    fn drop(&mut self) {
+        for channel in self.0.channels().iter().filter_map(Weak::upgrade) {
+            channel.disconnect();
+        }
+    }
+}
+
+/// An [`Analyzer`] shared between threads, frames are read by a background thread and handed to any number of subscribers and callbacks
+///
+/// # Examples
+///
+/// ```
+/// use std::{sync::Arc, thread};
+///
+/// use frame_analyzer::{Analyzer, Backpressure, Filter, SharedAnalyzer};
+///
+/// # fn main() {
+/// # let _ = try_main();
+/// # }
+/// #
+/// # fn try_main() -> anyhow::Result<()> {
+/// # let app_pid = 1;
+/// let analyzer = Arc::new(SharedAnalyzer::new(Analyzer::new()?)?);
+/// analyzer.attach_app(app_pid)?;
+///
+/// let governor = analyzer.subscribe(Filter::all().pid(app_pid), Backpressure::Coalesce);
+/// let logger = analyzer.subscribe(Filter::all(), Backpressure::DropOldest(1024));
+///
+/// thread::spawn(move || {
+/// for event in logger.iter() {
+/// println!("process: {}, frametime: {:?}", event.pid, event.frametime);
+/// }
+/// });
+///
+/// # let _ = governor.try_recv();
+/// # return Ok(());
+/// while let Some(event) = governor.recv() {
+/// println!("latest frametime: {:?}", event.frametime);
+/// // adjust frequencies...
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct SharedAnalyzer {
+    inner: Arc<Inner>,
+    reader: Option<JoinHandle<()>>,
+}
+
+impl SharedAnalyzer {
+    /// Share `analyzer`, starting the background reader thread
+    ///
+    /// # Errors
+    ///
+    /// Returns `IOError` if the reader thread can't be spawned
+    pub fn new(analyzer: Analyzer) -> Result<Self> {
+        let inner = Arc::new(Inner {
+            analyzer: Mutex::new(analyzer),
+            channels: Mutex::new(Vec::new()),
+            callbacks: Callbacks::default(),
+            running: AtomicBool::new(true),
+        });
+
+        let reader = {
+            let inner = inner.clone();
+            thread::Builder::new()
+                .name("frame-analyzer".into())
+                .spawn(move || read_frames(&inner))?
+        };
+
+        Ok(Self {
+            inner,
+            reader: Some(reader),
+        })
+    }
+
+    /// Receive the frames passing `filter`
+    #[must_use]
+    pub fn subscribe(&self, filter: Filter, policy: Backpressure) -> Subscription {
+        let channel = Arc::new(Channel {
+            filter,
+            policy,
+            queue: Mutex::new(Queue::default()),
+            readable: Condvar::new(),
+            writable: Condvar::new(),
+        });
+
+        self.inner.channels().push(Arc::downgrade(&channel));
+        Subscription { channel }
+    }
+
+    /// Run `callback` on the reader thread for every frame, until the returned guard is dropped
+    ///
+    /// Callbacks hold back the reader thread while they run, so keep them short.
+    /// A callback may still be running once more right after its guard is dropped.
+    /// Callbacks must not panic, with `panic = "abort"` that takes the whole process down, otherwise it stops the reader thread
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use frame_analyzer::{Analyzer, SharedAnalyzer};
+    ///
+    /// # fn main() {
+    /// # let _ = try_main();
+    /// # }
+    /// #
+    /// # fn try_main() -> anyhow::Result<()> {
+    /// # let app_pid = 1;
+    /// let analyzer = SharedAnalyzer::new(Analyzer::new()?)?;
+    /// analyzer.attach_app(app_pid)?;
+    ///
+    /// let guard = analyzer.on_frame(|event| {
+    /// println!("process: {}, frametime: {:?}", event.pid, event.frametime);
+    /// });
+    ///
+    /// // do some useful work for awhile
+    /// guard.unregister();
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn on_frame(&self, callback: impl FnMut(&FrameEvent) + Send + 'static) -> CallbackGuard {
+        self.inner.callbacks.register(callback)
+    }
+
+    /// See [`Analyzer::attach_app`]
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Analyzer::attach_app`]
+    pub fn attach_app(&self, pid: Pid) -> Result<()> {
+        self.inner.analyzer().attach_app(pid)
+    }
+
+    /// See [`Analyzer::detach_app`]
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Analyzer::detach_app`]
+    pub fn detach_app(&self, pid: Pid) -> Result<()> {
+        self.inner.analyzer().detach_app(pid)
+    }
+
+    /// See [`Analyzer::detach_apps`]
+    pub fn detach_apps(&self) {
+        self.inner.analyzer().detach_apps();
+    }
+
+    /// See [`Analyzer::contains`]
+    #[must_use]
+    pub fn contains(&self, pid: Pid) -> bool {
+        self.inner.analyzer().contains(pid)
+    }
+
+    /// Run `f` with exclusive access to the analyzer, e.g. to follow the foreground app.
+    /// Frames received by `f` don't reach the subscribers nor the callbacks
+    pub fn with<R>(&self, f: impl FnOnce(&mut Analyzer) -> R) -> R {
+        f(&mut self.inner.analyzer())
+    }
+}
+
+impl Drop for SharedAnalyzer {
+    fn drop(&mut self) {
+        self.inner.running.store(false, Ordering::Release);
+
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+    }
+}
+
+fn read_frames(inner: &Inner) {
+    let _disconnect = Disconnect(inner);
+    let mut events = Vec::new();
+
+    while inner.running.load(Ordering::Acquire) {
+        let idle = {
+            let mut analyzer = inner.analyzer();
+            if let Some(event) = analyzer.recv_event_timeout(READ_INTERVAL) {
+                events.push(event);
+            }
+            analyzer.drain_into(&mut events);
+            analyzer.pids().next().is_none() || analyzer.ended()
+        };
+
+        if events.is_empty() {
+            if idle {
+                // nothing attached or nothing left, the analyzer returns right away
+                thread::sleep(READ_INTERVAL);
+            }
+            continue;
+        }
+
+        inner.callbacks.run(&events);
+
+        let channels: Vec<Arc<Channel>> = {
+            let mut channels = inner.channels();
+            channels.retain(|channel| channel.strong_count() > 0);
+            channels.iter().filter_map(Weak::upgrade).collect()
+        };
+
+        for event in &events {
+            for channel in &channels {
+                if channel.filter.matches(event) {
+                    channel.push(*event, &inner.running);
+                }
+            }
+        }
+        events.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::Duration,
+    };
+
+    use super::{Backpressure, Filter, SharedAnalyzer};
+    use crate::{AnalyzerBuilder, Script, SyntheticSource};
+
+    #[test]
+    fn runs_callbacks_next_to_subscriptions() {
+        let source = SyntheticSource::new()
+            .script(Script::new(1000, 0x1).frames(60, Duration::from_millis(16)));
+        let analyzer = AnalyzerBuilder::new().source(source).build().unwrap();
+        let shared = SharedAnalyzer::new(analyzer).unwrap();
+
+        let subscription = shared.subscribe(Filter::all(), Backpressure::DropOldest(1024));
+        let frames = Arc::new(AtomicUsize::new(0));
+        let _counts = {
+            let frames = frames.clone();
+            shared.on_frame(move |_| {
+                frames.fetch_add(1, Ordering::Relaxed);
+            })
+        };
+        shared.attach_app(1000).unwrap();
+
+        assert_eq!(subscription.iter().take(60).count(), 60);
+        assert_eq!(frames.load(Ordering::Relaxed), 60);
+
+        drop(shared);
+        assert_eq!(subscription.recv(), None);
+    }
+}
@@ -0,0 +1,181 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{
+    collections::HashMap,
+    os::unix::io::{AsRawFd, RawFd},
+    time::Duration,
+};
+
+use mio::{Events, Interest, Poll, Token, event::Event, unix::SourceFd};
+
+use crate::{
+    Pid,
+    config::{self, AnalyzerConfig, ClockSource},
+    ebpf::{self, MemlockGuard},
+    error::Result,
+    event::RawFrame,
+    uprobe::UprobeHandler,
+};
+
+/// Where an [`crate::Analyzer`] gets its raw frames from
+///
+/// [`EbpfSource`] probes the apps on the device, [`crate::ReplaySource`] plays a recording back.
+/// Set with [`crate::AnalyzerBuilder::source`]
+pub trait FrameSource: Send {
+    /// Start delivering the frames of `pid`
+    ///
+    /// # Errors
+    ///
+    /// Whatever prevents the frames of `pid` from being delivered
+    fn attach(&mut self, pid: Pid) -> Result<()>;
+
+    /// Stop delivering the frames of `pid`
+    ///
+    /// # Errors
+    ///
+    /// Whatever goes wrong releasing `pid`
+    fn detach(&mut self, pid: Pid) -> Result<()>;
+
+    /// Wait up to `timeout` for frames of the attached processes and append them to `frames`, `None` waits until there are frames
+    ///
+    /// Returns without waiting if there can't be any frame anymore, e.g. at the end of a recording
+    ///
+    /// # Errors
+    ///
+    /// Whatever goes wrong waiting or reading
+    fn read(&mut self, frames: &mut Vec<RawFrame>, timeout: Option<Duration>) -> Result<()>;
+
+    /// The current time in nanoseconds, comparable with [`RawFrame::ktime_ns`]
+    fn now_ns(&self) -> u64;
+
+    /// Whether there can't be any frame anymore, `false` by default
+    fn ended(&self) -> bool {
+        false
+    }
+
+    /// A file descriptor that turns readable when there are frames to read, for event loops like the one of [`crate::AsyncAnalyzer`]
+    ///
+    /// `None` by default, the source is then read periodically
+    fn ready_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+/// Frames of the apps on the device, from the ebpf program attached to them
+///
+/// The source of [`crate::Analyzer::new`]
+pub struct EbpfSource {
+    poll: Poll,
+    events: Events,
+    uprobes: HashMap<Pid, UprobeHandler>,
+    config: AnalyzerConfig,
+    // dropped last, after the ebpf programs of the attached apps are unloaded
+    _memlock: Option<MemlockGuard>,
+}
+
+impl EbpfSource {
+    /// Create a source probing with `config`, applying its [`crate::Memlock`] policy
+    ///
+    /// # Errors
+    ///
+    /// `ConfigError` if the ring size isn't a power of 2 multiple of the page size,
+    /// `IOError` if the system selector can't be created and `MemlockError` if `RLIMIT_MEMLOCK` can't be raised
+    pub fn new(config: AnalyzerConfig) -> Result<Self> {
+        config.validate()?;
+        let memlock = ebpf::apply_memlock(config.memlock)?;
+
+        Ok(Self {
+            poll: Poll::new()?,
+            events: Events::with_capacity(config.event_capacity),
+            uprobes: HashMap::new(),
+            config,
+            _memlock: memlock,
+        })
+    }
+
+    /// The clock the frames are timestamped with
+    #[must_use]
+    pub const fn clock(&self) -> ClockSource {
+        self.config.clock
+    }
+}
+
+impl FrameSource for EbpfSource {
+    fn attach(&mut self, pid: Pid) -> Result<()> {
+        if self.uprobes.contains_key(&pid) {
+            return Ok(());
+        }
+
+        let mut uprobe = UprobeHandler::attach_app(pid, &self.config)?;
+        self.poll.registry().register(
+            &mut SourceFd(&uprobe.ring()?.as_raw_fd()),
+            Token(pid as usize),
+            Interest::READABLE,
+        )?;
+        self.uprobes.insert(pid, uprobe);
+
+        Ok(())
+    }
+
+    fn detach(&mut self, pid: Pid) -> Result<()> {
+        let Some(mut uprobe) = self.uprobes.remove(&pid) else {
+            return Ok(());
+        };
+
+        self.poll
+            .registry()
+            .deregister(&mut SourceFd(&uprobe.ring()?.as_raw_fd()))?;
+
+        Ok(())
+    }
+
+    fn read(&mut self, frames: &mut Vec<RawFrame>, timeout: Option<Duration>) -> Result<()> {
+        self.poll.poll(&mut self.events, timeout)?;
+
+        for pid in self.events.iter().map(event_to_pid) {
+            // registrations are edge-triggered, so the ring has to be drained on every event
+            if let Some(uprobe) = self.uprobes.get_mut(&pid) {
+                while let Some(signal) = uprobe.next_signal() {
+                    frames.push(RawFrame {
+                        pid,
+                        ktime_ns: signal.ktime_ns,
+                        buffer: signal.buffer,
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn now_ns(&self) -> u64 {
+        config::now_ns(self.config.clock)
+    }
+
+    // the selector of the rings, readable once any of them is
+    fn ready_fd(&self) -> Option<RawFd> {
+        Some(self.poll.as_raw_fd())
+    }
+}
+
+fn event_to_pid(event: &Event) -> Pid {
+    let token = event.token();
+    let Token(pid) = token;
+    pid as Pid
+}
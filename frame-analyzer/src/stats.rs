@@ -0,0 +1,472 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+#![allow(clippy::cast_precision_loss)]
+
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    time::Duration,
+};
+
+use crate::event::FrameEvent;
+
+/// The frames a [`FrameStats`] is computed over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Window {
+    /// The last `n` frames
+    Frames(usize),
+    /// The last frames whose frametimes add up to at most this long, the newest frame is always kept
+    Time(Duration),
+    /// Every frame pushed so far
+    All,
+}
+
+/// Everything [`FrameStats`] computes, taken at once with [`FrameStats::summary`]
+///
+/// Serializable with the `serde` feature
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FrameSummary {
+    /// How many frames are in the window
+    pub frames: usize,
+    /// Frames per second over the window
+    pub average_fps: f64,
+    /// Median frametime
+    pub p50: Duration,
+    /// 90th percentile frametime
+    pub p90: Duration,
+    /// 99th percentile frametime
+    pub p99: Duration,
+    /// Average fps of the slowest 1% of the frames
+    pub low_1: f64,
+    /// Average fps of the slowest 0.1% of the frames
+    pub low_0_1: f64,
+    /// Standard deviation of the frametimes
+    pub std_dev: Duration,
+    /// The longest frametime
+    pub max: Duration,
+}
+
+/// Rolling frametime statistics over a sliding [`Window`]
+///
+/// Fed with [`FrameStats::push`], [`FrameStats::push_event`] or any iterator of frametimes
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use frame_analyzer::{FrameStats, Window};
+///
+/// let mut stats = FrameStats::new(Window::Frames(120));
+/// stats.extend([Duration::from_millis(16); 99]);
+/// stats.push(Duration::from_millis(50));
+///
+/// assert_eq!(stats.max(), Some(Duration::from_millis(50)));
+/// assert!((stats.low(1.0).unwrap() - 20.0).abs() < 1e-9);
+/// ```
+///
+/// With an [`crate::Analyzer`]
+///
+/// ```
+/// # use frame_analyzer::{Analyzer, FrameStats, Window};
+/// # use std::time::Duration;
+/// #
+/// # fn main() {
+/// # let _ = try_main();
+/// # }
+/// #
+/// # fn try_main() -> anyhow::Result<()> {
+/// # let app_pid = 1;
+/// let mut analyzer = Analyzer::new()?;
+/// analyzer.attach_app(app_pid)?;
+///
+/// let mut stats = FrameStats::new(Window::Time(Duration::from_secs(1)));
+/// while let Some(event) = analyzer.recv_event_timeout(Duration::from_secs(1)) {
+/// stats.push_event(&event);
+/// println!("fps: {:?}", stats.average_fps());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct FrameStats {
+    window: Window,
+    frametimes: VecDeque<Duration>,
+    total: Duration,
+}
+
+impl Default for FrameStats {
+    fn default() -> Self {
+        Self::new(Window::All)
+    }
+}
+
+impl FrameStats {
+    /// Create empty statistics over `window`
+    #[must_use]
+    pub const fn new(window: Window) -> Self {
+        Self {
+            window,
+            frametimes: VecDeque::new(),
+            total: Duration::ZERO,
+        }
+    }
+
+    /// The window of the statistics
+    #[must_use]
+    pub const fn window(&self) -> Window {
+        self.window
+    }
+
+    /// Add a frame, dropping the frames that fall out of the window
+    pub fn push(&mut self, frametime: Duration) {
+        self.frametimes.push_back(frametime);
+        self.total += frametime;
+
+        match self.window {
+            Window::Frames(n) => {
+                while self.frametimes.len() > n {
+                    self.pop_oldest();
+                }
+            }
+            Window::Time(duration) => {
+                while self.total > duration && self.frametimes.len() > 1 {
+                    self.pop_oldest();
+                }
+            }
+            Window::All => (),
+        }
+    }
+
+    /// Add the frame of an event
+    ///
+    /// Frames after an idle gap are skipped, their frametime is the gap and not how long the frame took
+    pub fn push_event(&mut self, event: &FrameEvent) {
+        if !event.after_idle {
+            self.push(event.frametime);
+        }
+    }
+
+    fn pop_oldest(&mut self) {
+        if let Some(frametime) = self.frametimes.pop_front() {
+            self.total -= frametime;
+        }
+    }
+
+    /// How many frames are in the window
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.frametimes.len()
+    }
+
+    /// Whether the window has no frame
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.frametimes.is_empty()
+    }
+
+    /// Forget every frame
+    pub fn clear(&mut self) {
+        self.frametimes.clear();
+        self.total = Duration::ZERO;
+    }
+
+    /// The frametimes in the window, oldest first
+    #[must_use]
+    pub fn frametimes(&self) -> impl ExactSizeIterator<Item = Duration> + '_ {
+        self.frametimes.iter().copied()
+    }
+
+    /// The average frametime
+    #[must_use]
+    pub fn average_frametime(&self) -> Option<Duration> {
+        let len = u32::try_from(self.len()).ok().filter(|len| *len > 0)?;
+        Some(self.total / len)
+    }
+
+    /// Frames per second over the window
+    #[must_use]
+    pub fn average_fps(&self) -> Option<f64> {
+        if self.is_empty() || self.total.is_zero() {
+            return None;
+        }
+
+        Some(self.len() as f64 / self.total.as_secs_f64())
+    }
+
+    /// The frametime `percentile` percent of the frames are at most as long as, nearest-rank
+    #[must_use]
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        percentile_of(&self.sorted(), percentile)
+    }
+
+    /// Average fps of the slowest `percent` percent of the frames, at least one frame
+    ///
+    /// `low(1.0)` and `low(0.1)` are the usual 1% and 0.1% lows
+    #[must_use]
+    pub fn low(&self, percent: f64) -> Option<f64> {
+        low_of(&self.sorted(), percent)
+    }
+
+    /// Standard deviation of the frametimes
+    #[must_use]
+    pub fn std_dev(&self) -> Option<Duration> {
+        let average = self.average_frametime()?.as_secs_f64();
+        let variance = self
+            .frametimes
+            .iter()
+            .map(|frametime| (frametime.as_secs_f64() - average).powi(2))
+            .sum::<f64>()
+            / self.len() as f64;
+
+        Some(Duration::from_secs_f64(variance.sqrt()))
+    }
+
+    /// The longest frametime
+    #[must_use]
+    pub fn max(&self) -> Option<Duration> {
+        self.frametimes.iter().max().copied()
+    }
+
+    /// Every statistic at once, sorting the window only once
+    #[must_use]
+    pub fn summary(&self) -> Option<FrameSummary> {
+        let sorted = self.sorted();
+
+        Some(FrameSummary {
+            frames: self.len(),
+            average_fps: self.average_fps()?,
+            p50: percentile_of(&sorted, 50.0)?,
+            p90: percentile_of(&sorted, 90.0)?,
+            p99: percentile_of(&sorted, 99.0)?,
+            low_1: low_of(&sorted, 1.0)?,
+            low_0_1: low_of(&sorted, 0.1)?,
+            std_dev: self.std_dev()?,
+            max: *sorted.last()?,
+        })
+    }
+
+    fn sorted(&self) -> Vec<Duration> {
+        let mut sorted: Vec<_> = self.frametimes.iter().copied().collect();
+        sorted.sort_unstable();
+        sorted
+    }
+}
+
+fn percentile_of(sorted: &[Duration], percentile: f64) -> Option<Duration> {
+    let rank = (percentile.clamp(0.0, 100.0) / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted.get(rank.saturating_sub(1)).copied()
+}
+
+fn low_of(sorted: &[Duration], percent: f64) -> Option<f64> {
+    let count = ((percent.clamp(0.0, 100.0) / 100.0 * sorted.len() as f64).ceil() as usize).max(1);
+    let slowest = sorted.get(sorted.len().checked_sub(count)?..)?;
+    let total: Duration = slowest.iter().sum();
+
+    (!total.is_zero()).then(|| slowest.len() as f64 / total.as_secs_f64())
+}
+
+impl Extend<Duration> for FrameStats {
+    fn extend<I: IntoIterator<Item = Duration>>(&mut self, iter: I) {
+        for frametime in iter {
+            self.push(frametime);
+        }
+    }
+}
+
+impl Extend<FrameEvent> for FrameStats {
+    fn extend<I: IntoIterator<Item = FrameEvent>>(&mut self, iter: I) {
+        for event in iter {
+            self.push_event(&event);
+        }
+    }
+}
+
+impl FromIterator<Duration> for FrameStats {
+    fn from_iter<I: IntoIterator<Item = Duration>>(iter: I) -> Self {
+        let mut stats = Self::default();
+        stats.extend(iter);
+        stats
+    }
+}
+
+/// A [`FrameStats`] per key, e.g. per pid or per `(pid, surface)`
+///
+/// # Examples
+///
+/// ```
+/// # use frame_analyzer::{Analyzer, FrameStats, KeyedFrameStats, Pid, Window};
+/// #
+/// # fn main() {
+/// # let _ = try_main();
+/// # }
+/// #
+/// # fn try_main() -> anyhow::Result<()> {
+/// # let app_pid = 1;
+/// let mut analyzer = Analyzer::new()?;
+/// analyzer.attach_app(app_pid)?;
+///
+/// let mut stats: KeyedFrameStats<Pid> = KeyedFrameStats::new(Window::Frames(120));
+/// for _ in 0..1000 {
+/// stats.extend(analyzer.recv());
+/// }
+///
+/// if let Some(fps) = stats.get(&app_pid).and_then(FrameStats::average_fps) {
+/// println!("fps: {fps:.2}");
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct KeyedFrameStats<K> {
+    window: Window,
+    stats: HashMap<K, FrameStats>,
+}
+
+impl<K: Hash + Eq> KeyedFrameStats<K> {
+    /// Create empty statistics, every key gets its own `window`
+    #[must_use]
+    pub fn new(window: Window) -> Self {
+        Self {
+            window,
+            stats: HashMap::new(),
+        }
+    }
+
+    /// Add a frame of `key`
+    pub fn push(&mut self, key: K, frametime: Duration) {
+        self.entry(key).push(frametime);
+    }
+
+    /// Add the frame of an event to `key`, see [`FrameStats::push_event`]
+    pub fn push_event(&mut self, key: K, event: &FrameEvent) {
+        self.entry(key).push_event(event);
+    }
+
+    fn entry(&mut self, key: K) -> &mut FrameStats {
+        let window = self.window;
+        self.stats
+            .entry(key)
+            .or_insert_with(|| FrameStats::new(window))
+    }
+
+    /// The statistics of `key`
+    #[must_use]
+    pub fn get(&self, key: &K) -> Option<&FrameStats> {
+        self.stats.get(key)
+    }
+
+    /// Forget the statistics of `key`, e.g. once its app is detached
+    pub fn remove(&mut self, key: &K) -> Option<FrameStats> {
+        self.stats.remove(key)
+    }
+
+    /// Every key with its statistics
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &FrameStats)> {
+        self.stats.iter()
+    }
+
+    /// Forget every key
+    pub fn clear(&mut self) {
+        self.stats.clear();
+    }
+}
+
+impl<K: Hash + Eq> Extend<(K, Duration)> for KeyedFrameStats<K> {
+    fn extend<I: IntoIterator<Item = (K, Duration)>>(&mut self, iter: I) {
+        for (key, frametime) in iter {
+            self.push(key, frametime);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{FrameStats, Window};
+    use crate::test_util::event;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn keeps_the_window() {
+        let mut frames = FrameStats::new(Window::Frames(3));
+        frames.extend([ms(10), ms(20), ms(30), ms(40)]);
+        assert_eq!(
+            frames.frametimes().collect::<Vec<_>>(),
+            [ms(20), ms(30), ms(40)]
+        );
+        assert_eq!(frames.average_frametime(), Some(ms(30)));
+
+        let mut time = FrameStats::new(Window::Time(ms(50)));
+        time.extend([ms(10), ms(20), ms(30)]);
+        assert_eq!(time.frametimes().collect::<Vec<_>>(), [ms(20), ms(30)]);
+        // the newest frame stays even if it's longer than the window
+        time.push(ms(100));
+        assert_eq!(time.frametimes().collect::<Vec<_>>(), [ms(100)]);
+    }
+
+    #[test]
+    fn computes_percentiles_and_lows() {
+        let stats: FrameStats = (1..=100).map(ms).collect();
+
+        assert_eq!(stats.percentile(50.0), Some(ms(50)));
+        assert_eq!(stats.percentile(99.0), Some(ms(99)));
+        assert_eq!(stats.percentile(0.0), Some(ms(1)));
+        assert_eq!(stats.max(), Some(ms(100)));
+        // the slowest frame alone
+        assert_eq!(stats.low(1.0), Some(10.0));
+        assert_eq!(stats.low(0.1), Some(10.0));
+        // the slowest 2 frames, 99ms and 100ms
+        let low = stats.low(2.0).unwrap();
+        assert!((low - 2.0 / 0.199).abs() < 1e-9);
+
+        let summary = stats.summary().unwrap();
+        assert_eq!(summary.frames, 100);
+        assert!((summary.average_fps - 100.0 / 5.05).abs() < 1e-9);
+        assert_eq!(summary.p90, ms(90));
+    }
+
+    #[test]
+    fn skips_frames_after_idle() {
+        let mut stats = FrameStats::default();
+        stats.extend([
+            event(ms(16), false),
+            event(ms(5000), true),
+            event(ms(16), false),
+        ]);
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats.max(), Some(ms(16)));
+        assert_eq!(stats.std_dev(), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn has_nothing_without_frames() {
+        let mut stats = FrameStats::default();
+        assert_eq!(stats.summary(), None);
+
+        stats.push(Duration::ZERO);
+        assert_eq!(stats.average_fps(), None);
+        assert_eq!(stats.low(1.0), None);
+    }
+}
@@ -0,0 +1,61 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::time::Duration;
+
+use crate::{
+    event::FrameEvent,
+    jank::{FrameJank, JankConfig, JankDetector},
+    stats::{FrameStats, Window},
+};
+
+// the rolling fps averages the frames of this last period
+const FPS_WINDOW: Duration = Duration::from_secs(1);
+
+/// The metrics the exporters attach to a frame
+pub struct FrameMetrics {
+    /// Average fps over the last second, `None` until there's a frame
+    pub fps: Option<f64>,
+    /// `None` for a frame ending an idle period
+    pub jank: Option<FrameJank>,
+}
+
+/// Derives the metrics of the frames of one surface
+pub struct SurfaceMetrics {
+    stats: FrameStats,
+    jank: JankDetector,
+}
+
+impl SurfaceMetrics {
+    pub fn new(jank: JankConfig) -> Self {
+        Self {
+            stats: FrameStats::new(Window::Time(FPS_WINDOW)),
+            jank: JankDetector::new(jank),
+        }
+    }
+
+    pub fn push(&mut self, event: &FrameEvent) -> FrameMetrics {
+        self.stats.push_event(event);
+        let jank = self.jank.push_event(event);
+
+        FrameMetrics {
+            fps: self.stats.average_fps(),
+            jank,
+        }
+    }
+}
@@ -0,0 +1,313 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{
+    collections::{HashSet, VecDeque},
+    time::Duration,
+};
+
+use crate::{Pid, error::Result, event::RawFrame, source::FrameSource};
+
+// frames handed out by one read
+const BATCH: usize = 1024;
+
+/// The schedule of the frames one process queues, for a [`SyntheticSource`]
+///
+/// Steps run one after another on a clock starting at 0, frames are queued on the current surface.
+/// A surface queues one extra buffer at the start of its first step, it only marks the start of its first frame,
+/// so `frames(120, ..)` makes 120 frames
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use frame_analyzer::Script;
+///
+/// let period = Duration::from_micros(16_667);
+///
+/// // 60 fps, 30 fps with its first frame dropped, 2 seconds of a static screen, then 60 fps on another surface
+/// let script = Script::new(1000, 0x1)
+///     .frames(60, period)
+///     .drop(1)
+///     .frames(30, period * 2)
+///     .idle(Duration::from_secs(2))
+///     .surface(0x2)
+///     .frames(60, period);
+///
+/// // every surface queues one more buffer to start its first frame
+/// assert_eq!(script.len(), 1 + 60 + 29 + 1 + 60);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Script {
+    pid: Pid,
+    surface: usize,
+    now_ns: u64,
+    started: HashSet<usize>,
+    gap: Option<Duration>,
+    drops: usize,
+    frames: Vec<RawFrame>,
+}
+
+impl Script {
+    /// Schedule the frames of `pid`, starting on `surface`
+    #[must_use]
+    pub fn new(pid: Pid, surface: usize) -> Self {
+        Self {
+            pid,
+            surface,
+            now_ns: 0,
+            started: HashSet::new(),
+            gap: None,
+            drops: 0,
+            frames: Vec::new(),
+        }
+    }
+
+    /// Queue `count` frames, each `frametime` after the previous one
+    #[must_use]
+    pub fn frames(self, count: usize, frametime: Duration) -> Self {
+        self.pattern(count, &[frametime])
+    }
+
+    /// Queue `count` frames, their frametimes cycling through `frametimes`, e.g. `[8ms, 25ms]` for uneven pacing
+    #[must_use]
+    pub fn pattern(mut self, count: usize, frametimes: &[Duration]) -> Self {
+        if self.started.insert(self.surface) {
+            self.queue();
+        }
+
+        for frametime in frametimes.iter().cycle().take(count) {
+            let step = self.gap.take().unwrap_or(*frametime);
+            self.now_ns += step.as_nanos() as u64;
+
+            if self.drops > 0 {
+                self.drops -= 1;
+            } else {
+                self.queue();
+            }
+        }
+
+        self
+    }
+
+    /// Queue the next frames on `surface`, the previous surface stops drawing
+    #[must_use]
+    pub const fn surface(mut self, surface: usize) -> Self {
+        self.surface = surface;
+        self
+    }
+
+    /// Don't queue anything for `duration`, the next frame comes exactly `duration` after the last one
+    ///
+    /// Long enough gaps are idle periods, see [`crate::AnalyzerBuilder::idle_threshold`]
+    #[must_use]
+    pub fn idle(mut self, duration: Duration) -> Self {
+        let gap = self.gap.unwrap_or_default() + duration;
+        self.gap = Some(gap);
+        self
+    }
+
+    /// Lose the next `count` frames before they reach the analyzer, as when a ring overflows
+    ///
+    /// The frame after them spans the lost ones
+    #[must_use]
+    pub const fn drop(mut self, count: usize) -> Self {
+        self.drops += count;
+        self
+    }
+
+    /// How many buffers are queued
+    #[must_use]
+    pub const fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Whether no buffer is queued
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// When the schedule ends, including a trailing idle gap, in nanoseconds
+    #[must_use]
+    pub fn end_ns(&self) -> u64 {
+        self.now_ns + self.gap.map_or(0, |gap| gap.as_nanos() as u64)
+    }
+
+    fn queue(&mut self) {
+        self.frames.push(RawFrame {
+            pid: self.pid,
+            ktime_ns: self.now_ns,
+            buffer: self.surface,
+        });
+    }
+}
+
+/// Frames from [`Script`]s, no root nor kernel needed
+///
+/// The frames of every script are merged in time and handed out as fast as they're read, so the analysis is deterministic.
+/// The time of the source is the one of the last frame read, and the end of the longest script once every frame is read.
+/// Like at the end of a recording, receiving returns `None` right away once every frame is read, see [`crate::Analyzer::ended`]
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use frame_analyzer::{AnalyzerBuilder, IdleEvent, Script, SurfaceSelection, SyntheticSource};
+///
+/// # fn main() {
+/// # try_main().unwrap();
+/// # }
+/// #
+/// # fn try_main() -> anyhow::Result<()> {
+/// let period = Duration::from_micros(16_667);
+///
+/// // the app moves from a loading screen to the game, and pauses for 3 seconds
+/// let source = SyntheticSource::new().script(
+/// Script::new(1000, 0x1)
+/// .frames(60, period)
+/// .surface(0x2)
+/// .frames(60, period)
+/// .idle(Duration::from_secs(3))
+/// .frames(60, period),
+/// );
+///
+/// let mut analyzer = AnalyzerBuilder::new()
+/// .surface_selection(SurfaceSelection::Newest)
+/// .source(source)
+/// .build()?;
+/// analyzer.attach_app(1000)?;
+///
+/// let mut events = Vec::new();
+/// while let Some(event) = analyzer.recv_event() {
+/// events.push(event);
+/// }
+///
+/// assert_eq!(events.len(), 180);
+/// assert!(events[..60].iter().all(|event| event.surface == 0x1));
+/// assert!(events[60..].iter().all(|event| event.surface == 0x2));
+/// assert!(events[120].after_idle);
+///
+/// assert!(matches!(analyzer.try_recv_idle(), Some(IdleEvent::Start { .. })));
+/// assert!(matches!(analyzer.try_recv_idle(), Some(IdleEvent::End { idle, .. }) if idle == Duration::from_secs(3)));
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The surface with the longest history is the one analyzed by default, whatever else the app draws
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use frame_analyzer::{AnalyzerBuilder, Script, SyntheticSource};
+///
+/// # fn main() {
+/// # try_main().unwrap();
+/// # }
+/// #
+/// # fn try_main() -> anyhow::Result<()> {
+/// let game = Script::new(1000, 0x1).frames(300, Duration::from_micros(16_667));
+/// let overlay = Script::new(1000, 0x2).frames(10, Duration::from_millis(500));
+///
+/// let source = SyntheticSource::new().script(game).script(overlay);
+/// let mut analyzer = AnalyzerBuilder::new().source(source).build()?;
+/// analyzer.attach_app(1000)?;
+///
+/// while let Some(event) = analyzer.recv_event() {
+/// assert_eq!(event.surface, 0x1);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SyntheticSource {
+    frames: VecDeque<RawFrame>,
+    attached: HashSet<Pid>,
+    now_ns: u64,
+    end_ns: u64,
+}
+
+impl SyntheticSource {
+    /// A source without any frame
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the frames of `script`
+    #[must_use]
+    pub fn script(mut self, script: Script) -> Self {
+        self.end_ns = self.end_ns.max(script.end_ns());
+        self.frames.extend(script.frames);
+        // stable, frames queued at the same time stay in script order
+        self.frames
+            .make_contiguous()
+            .sort_by_key(|frame| frame.ktime_ns);
+        self
+    }
+
+    /// How many frames haven't been read yet, including the ones of processes that aren't attached
+    #[must_use]
+    pub fn remaining(&self) -> usize {
+        self.frames.len()
+    }
+}
+
+impl FrameSource for SyntheticSource {
+    fn attach(&mut self, pid: Pid) -> Result<()> {
+        self.attached.insert(pid);
+        Ok(())
+    }
+
+    fn detach(&mut self, pid: Pid) -> Result<()> {
+        self.attached.remove(&pid);
+        Ok(())
+    }
+
+    fn read(&mut self, frames: &mut Vec<RawFrame>, _timeout: Option<Duration>) -> Result<()> {
+        // every frame was handled, the time can move to the end of a trailing idle gap
+        if self.frames.is_empty() {
+            self.now_ns = self.end_ns;
+            return Ok(());
+        }
+
+        let len = frames.len();
+        while frames.len() - len < BATCH {
+            let Some(frame) = self.frames.pop_front() else {
+                break;
+            };
+
+            self.now_ns = frame.ktime_ns;
+            if self.attached.contains(&frame.pid) {
+                frames.push(frame);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn now_ns(&self) -> u64 {
+        self.now_ns
+    }
+
+    fn ended(&self) -> bool {
+        self.frames.is_empty() && self.now_ns == self.end_ns
+    }
+}
@@ -0,0 +1,408 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    fs::{self, File},
+    io::{self, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::{
+    Pid,
+    config::AnalyzerConfig,
+    error::{AnalyzerError, Result},
+    event::FrameEvent,
+    jank::JankConfig,
+    record::{self, Recording},
+    surface_metrics::{FrameMetrics, SurfaceMetrics},
+};
+
+/// The format of a [`TableSink`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableFormat {
+    /// Comma separated values with a header line
+    Csv,
+    /// A JSON object per line, keyed by the column names
+    JsonLines,
+}
+
+/// A column of a [`TableSink`], parsed from its name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Column {
+    /// `timestamp_ns`, when the frame was queued
+    Timestamp,
+    /// `pid`
+    Pid,
+    /// `surface`, in hex
+    Surface,
+    /// `seq`, see [`FrameEvent::seq`]
+    Seq,
+    /// `frametime_ms`
+    Frametime,
+    /// `after_idle`, see [`FrameEvent::after_idle`]
+    AfterIdle,
+    /// `jank`, `Jank` or `BigJank` for the janks of [`crate::JankDetector`], empty (`null`) otherwise
+    Jank,
+    /// `janky`, whether the frame missed the target period of the [`JankConfig`]
+    Janky,
+    /// `fps`, the average fps of the surface over the last second
+    Fps,
+}
+
+impl Column {
+    /// Every column, in their usual order
+    pub const ALL: [Self; 9] = [
+        Self::Timestamp,
+        Self::Pid,
+        Self::Surface,
+        Self::Seq,
+        Self::Frametime,
+        Self::AfterIdle,
+        Self::Jank,
+        Self::Janky,
+        Self::Fps,
+    ];
+
+    /// The name of the column in the header or the JSON objects
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Timestamp => "timestamp_ns",
+            Self::Pid => "pid",
+            Self::Surface => "surface",
+            Self::Seq => "seq",
+            Self::Frametime => "frametime_ms",
+            Self::AfterIdle => "after_idle",
+            Self::Jank => "jank",
+            Self::Janky => "janky",
+            Self::Fps => "fps",
+        }
+    }
+}
+
+impl FromStr for Column {
+    type Err = AnalyzerError;
+
+    /// The name of the column, `timestamp` and `frametime` work too
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "timestamp" => Ok(Self::Timestamp),
+            "frametime" => Ok(Self::Frametime),
+            _ => Self::ALL
+                .into_iter()
+                .find(|column| column.name() == name)
+                .ok_or_else(|| AnalyzerError::ConfigError(format!("unknown column `{name}`"))),
+        }
+    }
+}
+
+/// When a [`TableSink`] writing to a file starts a new one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    /// Once the file holds this many bytes
+    Size(u64),
+    /// Once the file holds frames this long apart
+    Interval(Duration),
+}
+
+/// Writes a row per frame as CSV or JSON lines, for spreadsheets and pandas
+///
+/// The columns default to timestamp, pid, surface, frametime, jank and fps.
+/// Files can be rotated: the full file is renamed with the next free suffix, `frames.csv.1`, `frames.csv.2`...,
+/// and a new one is started with its own header
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use frame_analyzer::{AnalyzerBuilder, Column, Script, SyntheticSource, TableFormat, TableSink};
+///
+/// # fn main() {
+/// # try_main().unwrap();
+/// # }
+/// #
+/// # fn try_main() -> anyhow::Result<()> {
+/// let script = Script::new(1000, 0x1).frames(10, Duration::from_millis(20));
+/// let mut analyzer = AnalyzerBuilder::new()
+/// .source(SyntheticSource::new().script(script))
+/// .build()?;
+/// analyzer.attach_app(1000)?;
+///
+/// let path = std::env::temp_dir().join("frame-analyzer-doc-frames.csv");
+/// let mut sink = TableSink::create(TableFormat::Csv, &path)?
+/// .columns(["timestamp", "frametime", "fps"].map(|name| name.parse::<Column>().unwrap()));
+/// while let Some(event) = analyzer.recv_event() {
+/// sink.push(&event)?;
+/// }
+/// sink.finish()?;
+///
+/// let csv = std::fs::read_to_string(&path)?;
+/// let mut lines = csv.lines();
+/// assert_eq!(lines.next(), Some("timestamp_ns,frametime_ms,fps"));
+/// assert_eq!(lines.next(), Some("20000000,20.000,50.00"));
+/// assert_eq!(lines.count(), 9);
+/// # Ok(())
+/// # }
+/// ```
+pub struct TableSink {
+    format: TableFormat,
+    columns: Vec<Column>,
+    jank: JankConfig,
+    writer: Box<dyn Write + Send>,
+    // set when writing to a file, the only output that can be rotated
+    path: Option<PathBuf>,
+    rotation: Option<Rotation>,
+    written: u64,
+    first_ns: Option<u64>,
+    header_due: bool,
+    surfaces: HashMap<(Pid, usize), SurfaceMetrics>,
+    line: String,
+}
+
+impl TableSink {
+    /// Write into `writer`
+    #[must_use]
+    pub fn new(format: TableFormat, writer: impl Write + Send + 'static) -> Self {
+        Self {
+            format,
+            columns: vec![
+                Column::Timestamp,
+                Column::Pid,
+                Column::Surface,
+                Column::Frametime,
+                Column::Jank,
+                Column::Fps,
+            ],
+            jank: JankConfig::default(),
+            writer: Box::new(writer),
+            path: None,
+            rotation: None,
+            written: 0,
+            first_ns: None,
+            header_due: format == TableFormat::Csv,
+            surfaces: HashMap::new(),
+            line: String::new(),
+        }
+    }
+
+    /// Write to the standard output
+    #[must_use]
+    pub fn stdout(format: TableFormat) -> Self {
+        Self::new(format, io::stdout())
+    }
+
+    /// Write into a new file, which can be rotated
+    ///
+    /// # Errors
+    ///
+    /// `IOError` if the file can't be created
+    pub fn create(format: TableFormat, path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut sink = Self::new(format, BufWriter::new(File::create(path)?));
+        sink.path = Some(path.to_path_buf());
+        Ok(sink)
+    }
+
+    /// The columns of every row, in order
+    #[must_use]
+    pub fn columns(mut self, columns: impl IntoIterator<Item = Column>) -> Self {
+        self.columns = columns.into_iter().collect();
+        self
+    }
+
+    /// Rotate the file, ignored unless writing to a file
+    #[must_use]
+    pub const fn rotate(mut self, rotation: Rotation) -> Self {
+        self.rotation = Some(rotation);
+        self
+    }
+
+    /// The thresholds of the `jank` and `janky` columns of the surfaces seen from now on, [`JankConfig::default`] by default
+    #[must_use]
+    pub const fn jank_config(mut self, config: JankConfig) -> Self {
+        self.jank = config;
+        self
+    }
+
+    /// Add the row of a frame
+    ///
+    /// # Errors
+    ///
+    /// `IOError` if writing or rotating fails
+    pub fn push(&mut self, event: &FrameEvent) -> Result<()> {
+        let metrics = self
+            .surfaces
+            .entry((event.pid, event.surface))
+            .or_insert_with(|| SurfaceMetrics::new(self.jank))
+            .push(event);
+
+        if self.rotation_due(event.timestamp_ns) {
+            self.rotate_file()?;
+        }
+        self.first_ns.get_or_insert(event.timestamp_ns);
+
+        if self.header_due {
+            self.header_due = false;
+            let header = self
+                .columns
+                .iter()
+                .map(|column| column.name())
+                .collect::<Vec<_>>()
+                .join(",");
+            self.line.push_str(&header);
+            self.line.push('\n');
+        }
+
+        match self.format {
+            TableFormat::Csv => self.csv_row(event, &metrics),
+            TableFormat::JsonLines => self.json_row(event, &metrics),
+        }
+        self.line.push('\n');
+
+        let result = self.writer.write_all(self.line.as_bytes());
+        self.written += self.line.len() as u64;
+        self.line.clear();
+        Ok(result?)
+    }
+
+    fn csv_row(&mut self, event: &FrameEvent, metrics: &FrameMetrics) {
+        for (i, column) in self.columns.iter().enumerate() {
+            if i > 0 {
+                self.line.push(',');
+            }
+            write_value(&mut self.line, *column, event, metrics, false);
+        }
+    }
+
+    fn json_row(&mut self, event: &FrameEvent, metrics: &FrameMetrics) {
+        self.line.push('{');
+        for (i, column) in self.columns.iter().enumerate() {
+            if i > 0 {
+                self.line.push(',');
+            }
+            let _ = write!(self.line, "\"{}\":", column.name());
+            write_value(&mut self.line, *column, event, metrics, true);
+        }
+        self.line.push('}');
+    }
+
+    fn rotation_due(&self, timestamp_ns: u64) -> bool {
+        if self.path.is_none() {
+            return false;
+        }
+
+        match self.rotation {
+            Some(Rotation::Size(size)) => self.written >= size,
+            Some(Rotation::Interval(interval)) => self.first_ns.is_some_and(|first_ns| {
+                timestamp_ns.saturating_sub(first_ns) >= interval.as_nanos() as u64
+            }),
+            None => false,
+        }
+    }
+
+    fn rotate_file(&mut self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        self.writer.flush()?;
+
+        let mut suffix = 1;
+        let rotated = loop {
+            let mut rotated = path.clone().into_os_string();
+            rotated.push(format!(".{suffix}"));
+            let rotated = PathBuf::from(rotated);
+            if !rotated.exists() {
+                break rotated;
+            }
+            suffix += 1;
+        };
+        fs::rename(path, &rotated)?;
+
+        self.writer = Box::new(BufWriter::new(File::create(path)?));
+        self.written = 0;
+        self.first_ns = None;
+        self.header_due = self.format == TableFormat::Csv;
+        Ok(())
+    }
+
+    /// Add the rows of every frame of a recording, as analyzed by an [`crate::Analyzer`] configured with `config`
+    ///
+    /// # Errors
+    ///
+    /// `IOError` if reading or writing fails and `RecordingError` if the recording is damaged
+    pub fn write_recording<R: Read>(
+        &mut self,
+        recording: &mut Recording<R>,
+        config: &AnalyzerConfig,
+    ) -> Result<()> {
+        record::analyze(recording, config, |event| self.push(event))
+    }
+
+    /// Flush the rows written so far
+    ///
+    /// # Errors
+    ///
+    /// `IOError` if writing fails
+    pub fn flush(&mut self) -> Result<()> {
+        Ok(self.writer.flush()?)
+    }
+
+    /// Flush and close the output
+    ///
+    /// # Errors
+    ///
+    /// `IOError` if writing fails
+    pub fn finish(mut self) -> Result<()> {
+        self.flush()
+    }
+}
+
+// strings are quoted and missing values are `null` in JSON, missing values are empty in CSV
+fn write_value(
+    out: &mut String,
+    column: Column,
+    event: &FrameEvent,
+    metrics: &FrameMetrics,
+    json: bool,
+) {
+    let quote = if json { "\"" } else { "" };
+    let missing = if json { "null" } else { "" };
+
+    let _ = match column {
+        Column::Timestamp => write!(out, "{}", event.timestamp_ns),
+        Column::Pid => write!(out, "{}", event.pid),
+        Column::Surface => write!(out, "{quote}{:#x}{quote}", event.surface),
+        Column::Seq => write!(out, "{}", event.seq),
+        Column::Frametime => write!(out, "{:.3}", event.frametime.as_secs_f64() * 1000.0),
+        Column::AfterIdle => write!(out, "{}", event.after_idle),
+        Column::Jank => match metrics.jank.and_then(|jank| jank.kind) {
+            Some(kind) => write!(out, "{quote}{kind:?}{quote}"),
+            None => write!(out, "{missing}"),
+        },
+        Column::Janky => write!(out, "{}", metrics.jank.is_some_and(|jank| jank.janky)),
+        Column::Fps => match metrics.fps {
+            Some(fps) => write!(out, "{fps:.2}"),
+            None => write!(out, "{missing}"),
+        },
+    };
+}
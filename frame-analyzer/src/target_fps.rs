@@ -0,0 +1,342 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+#![allow(clippy::cast_precision_loss)]
+
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    time::Duration,
+};
+
+use crate::event::FrameEvent;
+
+/// The settings of a [`TargetFpsEstimator`]
+///
+/// Serializable with the `serde` feature
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct TargetFpsConfig {
+    /// The frame rates an app may aim for, 30, 60, 90, 120 and 144 by default
+    pub candidates: Vec<u32>,
+    /// How many recent frames the estimate looks at, 120 by default
+    pub window: usize,
+    /// A frame is on target if its frametime is within this fraction of the target period, 0.15 by default
+    ///
+    /// Slower frames that are still nearest to the target count as well, they missed it
+    pub tolerance: f64,
+    /// The target only changes to an estimate at least this confident, 0.5 by default
+    pub min_confidence: f64,
+}
+
+impl Default for TargetFpsConfig {
+    fn default() -> Self {
+        Self {
+            candidates: vec![30, 60, 90, 120, 144],
+            window: 120,
+            tolerance: 0.15,
+            min_confidence: 0.5,
+        }
+    }
+}
+
+/// The frame rate an app most likely aims for
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TargetFps {
+    /// One of the [`TargetFpsConfig::candidates`]
+    pub fps: u32,
+    /// The fraction of the recent frames on target, from 0 to 1
+    pub confidence: f64,
+}
+
+/// The target of an app changed, e.g. its frame limiter was switched or it entered a menu
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TargetChange {
+    /// The target before, `None` for the first target found
+    pub previous: Option<TargetFps>,
+    /// The target now
+    pub current: TargetFps,
+}
+
+/// Estimates the target frame rate of one surface from its recent frametimes
+///
+/// The median frametime is matched to the nearest candidate on a log scale, so an app that aims for 60 fps
+/// and only makes 50 is still taken for 60. Frames that missed the target count towards its confidence
+/// as long as they are nearer to it than to any slower candidate
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use frame_analyzer::TargetFpsEstimator;
+///
+/// let mut estimator = TargetFpsEstimator::default();
+/// let changes: Vec<_> = (0..120)
+///     .filter_map(|_| estimator.push(Duration::from_micros(16_667)))
+///     .collect();
+///
+/// assert_eq!(changes.len(), 1);
+/// assert_eq!(changes[0].current.fps, 60);
+///
+/// // the game switches its frame limiter to 30 fps
+/// let change = (0..120)
+///     .find_map(|_| estimator.push(Duration::from_micros(33_333)))
+///     .unwrap();
+/// assert_eq!(change.previous.map(|target| target.fps), Some(60));
+/// assert_eq!(change.current.fps, 30);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct TargetFpsEstimator {
+    config: TargetFpsConfig,
+    frametimes: VecDeque<Duration>,
+    current: Option<TargetFps>,
+}
+
+impl TargetFpsEstimator {
+    /// Create an estimator without any frame
+    #[must_use]
+    pub fn new(config: TargetFpsConfig) -> Self {
+        Self {
+            frametimes: VecDeque::with_capacity(config.window),
+            config,
+            current: None,
+        }
+    }
+
+    /// The settings of the estimator
+    #[must_use]
+    pub const fn config(&self) -> &TargetFpsConfig {
+        &self.config
+    }
+
+    /// Add a frame, returns the change if the target changed
+    pub fn push(&mut self, frametime: Duration) -> Option<TargetChange> {
+        if self.frametimes.len() >= self.config.window {
+            self.frametimes.pop_front();
+        }
+        self.frametimes.push_back(frametime);
+
+        let estimate = self.estimate()?;
+        if let Some(current) = self
+            .current
+            .as_mut()
+            .filter(|current| current.fps == estimate.fps)
+        {
+            current.confidence = estimate.confidence;
+            return None;
+        }
+
+        if estimate.confidence < self.config.min_confidence {
+            return None;
+        }
+
+        let previous = self.current.replace(estimate);
+        Some(TargetChange {
+            previous,
+            current: estimate,
+        })
+    }
+
+    /// Add the frame of an event, frames after an idle gap are skipped
+    pub fn push_event(&mut self, event: &FrameEvent) -> Option<TargetChange> {
+        if event.after_idle {
+            return None;
+        }
+
+        self.push(event.frametime)
+    }
+
+    /// The current target, `None` until one was estimated confidently enough
+    #[must_use]
+    pub const fn current(&self) -> Option<TargetFps> {
+        self.current
+    }
+
+    /// Estimate the target from the recent frames right now, regardless of the confidence
+    ///
+    /// `None` until the window is half full
+    #[must_use]
+    pub fn estimate(&self) -> Option<TargetFps> {
+        if self.frametimes.is_empty() || self.frametimes.len() * 2 < self.config.window {
+            return None;
+        }
+
+        let mut sorted: Vec<_> = self.frametimes.iter().copied().collect();
+        sorted.sort_unstable();
+        let median = sorted[sorted.len() / 2].as_secs_f64();
+        if median <= 0.0 {
+            return None;
+        }
+
+        let fps = self.nearest(median)?;
+        let period = 1.0 / f64::from(fps);
+        let on_target = self
+            .frametimes
+            .iter()
+            .map(Duration::as_secs_f64)
+            .filter(|frametime| {
+                (frametime - period).abs() <= period * self.config.tolerance
+                    || (*frametime > period && self.nearest(*frametime) == Some(fps))
+            })
+            .count();
+
+        Some(TargetFps {
+            fps,
+            confidence: on_target as f64 / self.frametimes.len() as f64,
+        })
+    }
+
+    fn nearest(&self, frametime: f64) -> Option<u32> {
+        self.config
+            .candidates
+            .iter()
+            .copied()
+            .filter(|fps| *fps > 0)
+            .min_by(|a, b| {
+                let distance = |fps: u32| (f64::from(fps) * frametime).ln().abs();
+                distance(*a).total_cmp(&distance(*b))
+            })
+    }
+
+    /// Forget every frame and the current target
+    pub fn clear(&mut self) {
+        self.frametimes.clear();
+        self.current = None;
+    }
+}
+
+/// A [`TargetFpsEstimator`] per key, e.g. per pid or per `(pid, surface)`
+///
+/// # Examples
+///
+/// ```
+/// # use frame_analyzer::{Analyzer, KeyedTargetFps, Pid, TargetFpsConfig};
+/// #
+/// # fn main() {
+/// # let _ = try_main();
+/// # }
+/// #
+/// # fn try_main() -> anyhow::Result<()> {
+/// # let app_pid = 1;
+/// let mut analyzer = Analyzer::new()?;
+/// analyzer.attach_app(app_pid)?;
+///
+/// let mut targets: KeyedTargetFps<Pid> = KeyedTargetFps::new(TargetFpsConfig::default());
+/// while let Some((pid, frametime)) = analyzer.recv() {
+/// if let Some(change) = targets.push(pid, frametime) {
+/// println!("{pid} now aims for {} fps", change.current.fps);
+/// }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct KeyedTargetFps<K> {
+    config: TargetFpsConfig,
+    estimators: HashMap<K, TargetFpsEstimator>,
+}
+
+impl<K: Hash + Eq> KeyedTargetFps<K> {
+    /// Create estimators, every key gets its own with `config`
+    #[must_use]
+    pub fn new(config: TargetFpsConfig) -> Self {
+        Self {
+            config,
+            estimators: HashMap::new(),
+        }
+    }
+
+    /// Add a frame of `key`, returns the change if its target changed
+    pub fn push(&mut self, key: K, frametime: Duration) -> Option<TargetChange> {
+        self.entry(key).push(frametime)
+    }
+
+    /// Add the frame of an event to `key`, see [`TargetFpsEstimator::push_event`]
+    pub fn push_event(&mut self, key: K, event: &FrameEvent) -> Option<TargetChange> {
+        self.entry(key).push_event(event)
+    }
+
+    fn entry(&mut self, key: K) -> &mut TargetFpsEstimator {
+        let config = &self.config;
+        self.estimators
+            .entry(key)
+            .or_insert_with(|| TargetFpsEstimator::new(config.clone()))
+    }
+
+    /// The current target of `key`
+    #[must_use]
+    pub fn current(&self, key: &K) -> Option<TargetFps> {
+        self.estimators
+            .get(key)
+            .and_then(TargetFpsEstimator::current)
+    }
+
+    /// The estimator of `key`
+    #[must_use]
+    pub fn get(&self, key: &K) -> Option<&TargetFpsEstimator> {
+        self.estimators.get(key)
+    }
+
+    /// Forget the estimator of `key`, e.g. once its app is detached
+    pub fn remove(&mut self, key: &K) -> Option<TargetFpsEstimator> {
+        self.estimators.remove(key)
+    }
+
+    /// Every key with its estimator
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &TargetFpsEstimator)> {
+        self.estimators.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::TargetFpsEstimator;
+
+    #[test]
+    fn takes_missed_frames_for_the_target() {
+        let mut estimator = TargetFpsEstimator::default();
+        let change = (0..120)
+            .find_map(|_| estimator.push(Duration::from_millis(20)))
+            .unwrap();
+
+        assert_eq!(change.previous, None);
+        assert_eq!(change.current.fps, 60);
+        assert!(change.current.confidence >= 0.5);
+    }
+
+    #[test]
+    fn ignores_frames_faster_than_the_target() {
+        let mut estimator = TargetFpsEstimator::default();
+        for _ in 0..60 {
+            estimator.push(Duration::from_millis(14));
+        }
+        for _ in 0..60 {
+            estimator.push(Duration::from_millis(20));
+        }
+
+        let estimate = estimator.estimate().unwrap();
+        assert_eq!(estimate.fps, 60);
+        assert!((estimate.confidence - 0.5).abs() < f64::EPSILON);
+    }
+}
@@ -0,0 +1,60 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{
+    io::{self, Write},
+    mem,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::event::FrameEvent;
+
+/// A frame of surface `0x1` of pid 1
+pub const fn event(frametime: Duration, after_idle: bool) -> FrameEvent {
+    FrameEvent {
+        pid: 1,
+        timestamp_ns: 0,
+        frametime,
+        surface: 0x1,
+        seq: 0,
+        after_idle,
+    }
+}
+
+/// An in-memory writer, its clones share the written bytes
+#[derive(Clone, Default)]
+pub struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Buffer {
+    /// Takes the bytes written so far
+    pub fn take(&self) -> Vec<u8> {
+        mem::take(&mut self.0.lock().unwrap())
+    }
+}
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,239 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    io::Write,
+    time::Duration,
+};
+
+use crate::{
+    Pid,
+    event::FrameEvent,
+    jank::{JankConfig, JankKind},
+    surface_metrics::SurfaceMetrics,
+};
+
+// tracefs first, then its older mount under debugfs
+const TRACE_MARKER_PATHS: [&str; 2] = [
+    "/sys/kernel/tracing/trace_marker",
+    "/sys/kernel/debug/tracing/trace_marker",
+];
+
+struct Surface {
+    metrics: SurfaceMetrics,
+    // the frame whose async slice is open
+    open: Option<u64>,
+    jank: u8,
+    timestamp_ns: u64,
+}
+
+/// Writes atrace markers for every frame into the kernel trace, see [`crate::AnalyzerBuilder::trace_marker`]
+///
+/// Every marker is one write, as atrace does, and a failing or short write turns the markers off
+pub struct TraceMarker {
+    file: Option<File>,
+    jank: JankConfig,
+    idle_threshold: Duration,
+    surfaces: HashMap<(Pid, usize), Surface>,
+    marker: String,
+}
+
+impl TraceMarker {
+    /// Open the first marker file that can be written, markers are off if there's none
+    ///
+    /// Surfaces that don't draw for `idle_threshold` close their open slice
+    pub fn open(idle_threshold: Duration) -> Self {
+        let file = TRACE_MARKER_PATHS
+            .iter()
+            .find_map(|path| OpenOptions::new().write(true).open(path).ok());
+
+        Self {
+            file,
+            jank: JankConfig::default(),
+            idle_threshold,
+            surfaces: HashMap::new(),
+            marker: String::new(),
+        }
+    }
+
+    /// Mark a frame
+    ///
+    /// In the process of the frame, a `frame <surface>` async slice spans from a frame to the next one as the analyzer saw them,
+    /// or until the surface goes idle,
+    /// with `frametime_us <surface>` and `fps <surface>` counters and a `jank <surface>` counter, 1 for a jank and 2 for a big jank
+    pub fn frame(&mut self, event: &FrameEvent) {
+        if self.file.is_none() {
+            return;
+        }
+
+        let pid = event.pid;
+        let surface = self
+            .surfaces
+            .entry((pid, event.surface))
+            .or_insert_with(|| Surface {
+                metrics: SurfaceMetrics::new(self.jank),
+                open: None,
+                jank: 0,
+                timestamp_ns: 0,
+            });
+        let metrics = surface.metrics.push(event);
+        let previous = surface.open.replace(event.seq);
+        surface.timestamp_ns = event.timestamp_ns;
+        let jank = match metrics.jank.and_then(|jank| jank.kind) {
+            Some(JankKind::BigJank) => 2,
+            Some(JankKind::Jank) => 1,
+            None => 0,
+        };
+        let jank_changed = surface.jank != jank;
+        surface.jank = jank;
+        let name = event.surface;
+
+        if let Some(seq) = previous {
+            self.mark(format_args!("F|{pid}|frame {name:#x}|{seq}"));
+        }
+        self.mark(format_args!("S|{pid}|frame {name:#x}|{}", event.seq));
+        self.mark(format_args!(
+            "C|{pid}|frametime_us {name:#x}|{}",
+            event.frametime.as_micros()
+        ));
+        if let Some(fps) = metrics.fps {
+            self.mark(format_args!("C|{pid}|fps {name:#x}|{}", fps.round() as u32));
+        }
+        if jank_changed {
+            self.mark(format_args!("C|{pid}|jank {name:#x}|{jank}"));
+        }
+    }
+
+    /// Close the open slices of the surfaces that didn't draw for the idle threshold until `now_ns`
+    pub fn check(&mut self, now_ns: u64) {
+        if self.file.is_none() {
+            return;
+        }
+
+        let threshold = self.idle_threshold.as_nanos() as u64;
+        let mut idle = Vec::new();
+        for ((pid, surface), activity) in &mut self.surfaces {
+            if now_ns.saturating_sub(activity.timestamp_ns) < threshold {
+                continue;
+            }
+            if let Some(seq) = activity.open.take() {
+                idle.push((*pid, *surface, seq));
+            }
+        }
+
+        for (pid, surface, seq) in idle {
+            self.mark(format_args!("F|{pid}|frame {surface:#x}|{seq}"));
+        }
+    }
+
+    /// Close the open slices of a detached process
+    pub fn forget(&mut self, pid: Pid) {
+        let surfaces: Vec<_> = self
+            .surfaces
+            .keys()
+            .filter(|(surface_pid, _)| *surface_pid == pid)
+            .copied()
+            .collect();
+
+        for key in surfaces {
+            if let Some(Surface {
+                open: Some(seq), ..
+            }) = self.surfaces.remove(&key)
+            {
+                self.mark(format_args!("F|{pid}|frame {:#x}|{seq}", key.1));
+            }
+        }
+    }
+
+    /// Close every open slice
+    pub fn forget_all(&mut self) {
+        let pids: Vec<_> = self.surfaces.keys().map(|(pid, _)| *pid).collect();
+        for pid in pids {
+            self.forget(pid);
+        }
+    }
+
+    fn mark(&mut self, marker: std::fmt::Arguments<'_>) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+
+        self.marker.clear();
+        let _ = self.marker.write_fmt(marker);
+        // a marker split over several writes would be several events
+        if !file
+            .write(self.marker.as_bytes())
+            .is_ok_and(|written| written == self.marker.len())
+        {
+            self.file = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        fs::{self, File},
+        time::Duration,
+    };
+
+    use super::TraceMarker;
+    use crate::{event::FrameEvent, jank::JankConfig};
+
+    const PERIOD: Duration = Duration::from_micros(16_667);
+
+    #[test]
+    fn closes_the_slice_of_idle_surfaces() {
+        let path =
+            std::env::temp_dir().join(format!("frame-analyzer-marker-{}", std::process::id()));
+        let mut marker = TraceMarker {
+            file: Some(File::create(&path).unwrap()),
+            jank: JankConfig::default(),
+            idle_threshold: Duration::from_secs(1),
+            surfaces: HashMap::new(),
+            marker: String::new(),
+        };
+
+        for seq in 0..2 {
+            marker.frame(&FrameEvent {
+                pid: 1,
+                timestamp_ns: (seq + 1) * PERIOD.as_nanos() as u64,
+                frametime: PERIOD,
+                surface: 0x1,
+                seq,
+                after_idle: false,
+            });
+        }
+        marker.check(Duration::from_millis(500).as_nanos() as u64);
+        marker.check(Duration::from_secs(2).as_nanos() as u64);
+        // closed once
+        marker.check(Duration::from_secs(3).as_nanos() as u64);
+        marker.forget(1);
+
+        let markers = fs::read_to_string(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        assert!(marker.file.is_some());
+        // both slices opened and closed, the last one by the idle check only
+        assert_eq!(markers.matches("|frame 0x1|").count(), 4);
+        assert!(markers.ends_with("C|1|fps 0x1|60F|1|frame 0x1|1"));
+    }
+}
@@ -0,0 +1,190 @@
+/*
+ * Copyright (c) 2024 shadow3aaa@gitbub.com
+ *
+ * This file is part of frame-analyzer-ebpf.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::{
+    collections::HashSet,
+    fs,
+    os::unix::fs::MetadataExt,
+    time::{Duration, Instant},
+};
+
+use crate::{Pid, error::Result};
+
+/// The uid of an Android app
+pub type Uid = u32;
+
+// How often the processes of an attached uid are looked up again
+const RESCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The processes of an attached uid
+#[derive(Debug)]
+pub struct UidGroup {
+    uid: Uid,
+    last_scan: Option<Instant>,
+    /// All processes of the uid found by the last scan
+    pub members: HashSet<Pid>,
+    /// The members this group keeps attached, also when the foreground watcher or another group attached them first
+    pub attached: HashSet<Pid>,
+}
+
+impl UidGroup {
+    pub fn new(uid: Uid) -> Self {
+        Self {
+            uid,
+            last_scan: None,
+            members: HashSet::new(),
+            attached: HashSet::new(),
+        }
+    }
+
+    /// Look up the processes of the uid again if it's time to, returns the processes that appeared and disappeared
+    pub fn refresh(&mut self) -> Result<(Vec<Pid>, Vec<Pid>)> {
+        if self
+            .last_scan
+            .is_some_and(|last| last.elapsed() < RESCAN_INTERVAL)
+        {
+            return Ok((Vec::new(), Vec::new()));
+        }
+
+        self.last_scan = Some(Instant::now());
+        let current = processes_of(self.uid)?;
+
+        let gone: Vec<Pid> = self.members.difference(&current).copied().collect();
+        let new: Vec<Pid> = current.difference(&self.members).copied().collect();
+        self.members = current;
+
+        Ok((new, gone))
+    }
+
+    /// The longest time `Analyzer` may wait for frames before the uid has to be scanned again
+    pub fn wait_hint(&self) -> Duration {
+        self.last_scan.map_or(Duration::ZERO, |last| {
+            RESCAN_INTERVAL.saturating_sub(last.elapsed())
+        })
+    }
+}
+
+/// All processes in `/proc` owned by `uid`
+fn processes_of(uid: Uid) -> Result<HashSet<Pid>> {
+    let mut pids = HashSet::new();
+
+    for entry in fs::read_dir("/proc")? {
+        let entry = entry?;
+        let Some(pid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<Pid>().ok())
+        else {
+            continue;
+        };
+
+        // the process may have exited since the directory was listed
+        if entry.metadata().is_ok_and(|metadata| metadata.uid() == uid) {
+            pids.insert(pid);
+        }
+    }
+
+    Ok(pids)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process::Command, time::Duration};
+
+    use crate::{AnalyzerBuilder, ForegroundWatcher, Pid, Script, SyntheticSource, WatchMethod};
+
+    fn current() -> (Pid, u32) {
+        (std::process::id() as Pid, unsafe { libc::getuid() })
+    }
+
+    #[test]
+    fn skips_frames_of_other_processes() {
+        let (pid, uid) = current();
+        // no process has this pid, so it belongs to no uid
+        let stranger = Pid::MAX;
+
+        let period = Duration::from_micros(16_667);
+        let source = SyntheticSource::new()
+            .script(Script::new(stranger, 0x1).frames(60, period))
+            .script(Script::new(pid, 0x2).frames(10, period * 2));
+        let mut analyzer = AnalyzerBuilder::new().source(source).build().unwrap();
+        analyzer.attach_app(stranger).unwrap();
+        analyzer.attach_uid(uid).unwrap();
+
+        let (received, frametime) = analyzer.recv_uid().unwrap();
+        assert_eq!(received, uid);
+        assert_eq!(frametime, period * 2);
+    }
+
+    #[test]
+    fn keeps_the_frametimes_of_every_process() {
+        let (pid, uid) = current();
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+
+        let (slow, fast) = (Duration::from_millis(50), Duration::from_millis(33));
+        let source = SyntheticSource::new()
+            .script(Script::new(pid, 0x1).frames(20, slow))
+            .script(Script::new(child.id() as Pid, 0x2).frames(30, fast));
+        let mut analyzer = AnalyzerBuilder::new().source(source).build().unwrap();
+        analyzer.attach_uid(uid).unwrap();
+
+        // interleaved, but each as long as its own process draws
+        let frametimes: Vec<_> = std::iter::from_fn(|| analyzer.recv_uid()).collect();
+        assert_eq!(frametimes.len(), 50);
+        assert!(
+            frametimes
+                .iter()
+                .all(|(received, frametime)| *received == uid && [slow, fast].contains(frametime))
+        );
+
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+
+    #[test]
+    fn keeps_members_attached_by_the_foreground() {
+        let (pid, uid) = current();
+        let root = std::env::temp_dir().join(format!("frame-analyzer-uid-{pid}"));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("cgroup.procs"), format!("{pid}\n")).unwrap();
+
+        let mut analyzer = AnalyzerBuilder::new()
+            .source(SyntheticSource::new())
+            .build()
+            .unwrap();
+        analyzer
+            .follow_foreground(
+                ForegroundWatcher::new()
+                    .root(&root)
+                    .method(WatchMethod::Polling(Duration::ZERO)),
+            )
+            .unwrap();
+        analyzer.attach_uid(uid).unwrap();
+        assert!(analyzer.contains(pid));
+
+        // leaving the foreground doesn't detach a process of an attached uid
+        fs::write(root.join("cgroup.procs"), "").unwrap();
+        assert_eq!(analyzer.try_recv(), None);
+        assert!(analyzer.contains(pid));
+
+        analyzer.detach_uid(uid);
+        assert!(!analyzer.contains(pid));
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
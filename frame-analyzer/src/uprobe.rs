@@ -16,13 +16,17 @@
 * You should have received a copy of the GNU General Public License
 * along with this program. If not, see <https://www.gnu.org/licenses/>.
 */
+use std::ptr;
+
 use aya::{
     Ebpf,
     maps::{MapData, RingBuf},
-    programs::{ProgramError, UProbe},
+    programs::UProbe,
 };
 
-use crate::{ebpf::load_bpf, error::AnalyzerError, error::Result};
+use frame_analyzer_ebpf_common::FrameSignal;
+
+use crate::{config::AnalyzerConfig, ebpf::load_bpf, error::AnalyzerError, error::Result};
 
 pub struct UprobeHandler {
     bpf: Ebpf,
@@ -31,61 +35,76 @@ pub struct UprobeHandler {
 impl Drop for UprobeHandler {
     fn drop(&mut self) {
         // 修复：完善卸载错误的日志提示（可替换为项目日志库）
-        if let Err(e) = self.get_program().and_then(|p| p.unload()) {
+        if let Err(e) = self
+            .get_program()
+            .and_then(|p| p.unload().map_err(AnalyzerError::from))
+        {
             eprintln!("Failed to unload uprobe program: {e}");
         }
     }
 }
 
 impl UprobeHandler {
-    pub fn attach_app(pid: i32) -> Result<Self> {
-        let mut bpf = load_bpf()?;
+    pub fn attach_app(pid: i32, config: &AnalyzerConfig) -> Result<Self> {
+        let mut bpf = load_bpf(config)?;
 
         // 修复1：替换unwrap()，添加程序查找失败的错误处理
-        let program = bpf.program_mut("frame_analyzer_ebpf")
-            .ok_or_else(|| AnalyzerError::BpfProgramError(ProgramError::NotFound))?;
+        let program = bpf
+            .program_mut("frame_analyzer_ebpf")
+            .ok_or_else(program_not_found)?;
         let program: &mut UProbe = program.try_into()?;
 
         program.load()?;
 
-        // 尝试挂载第一个符号
-        let attach_result = program.attach(
-            Some("_ZN7android7Surface11queueBufferEP19ANativeWindowBufferi"),
-            0,
-            "/system/lib64/libgui.so",
-            Some(pid),
-        );
-
-        // 挂载失败则尝试第二个符号，并保留具体错误信息
-        if let Err(e1) = attach_result {
-            program.attach(
-                Some("_ZN7android7Surface11queueBufferEP19ANativeWindowBufferiPNS_24SurfaceQueueBufferOutputE"),
-                0,
-                "/system/lib64/libgui.so",
-                Some(pid),
-            ).map_err(|e2| AnalyzerError::UprobeAttachError(format!(
-                "Failed to attach both symbols: first {e1}, second {e2}"
-            )))?;
+        // 按顺序尝试挂载各个探针，全部失败时保留每一个具体错误信息
+        let mut errors = Vec::new();
+        for probe in &config.probes {
+            match program.attach(Some(&probe.symbol), 0, &probe.library, Some(pid)) {
+                Ok(_) => return Ok(Self { bpf }),
+                Err(e) => errors.push(format!("{}@{}: {e}", probe.symbol, probe.library)),
+            }
         }
 
-        Ok(Self { bpf })
+        Err(AnalyzerError::UprobeAttachError(format!(
+            "Failed to attach any probe target: {}",
+            errors.join(", ")
+        )))
     }
 
     pub fn ring(&mut self) -> Result<RingBuf<&mut MapData>> {
         // 修复2：替换unwrap()，添加Map查找失败的错误处理
-        let ring_map = self.bpf.map_mut("RING_BUF")
-            .ok_or_else(|| AnalyzerError::BpfMapError("RING_BUF not found".into()))?;
+        let ring_map = self
+            .bpf
+            .map_mut("RING_BUF")
+            .ok_or_else(|| AnalyzerError::FrameDataReadError("RING_BUF not found".into()))?;
         let ring: RingBuf<&mut MapData> = RingBuf::try_from(ring_map)?;
 
         Ok(ring)
     }
 
+    /// Take the next frame signal out of the ring, `None` if the ring is empty
+    pub fn next_signal(&mut self) -> Option<FrameSignal> {
+        let mut ring = self.ring().ok()?;
+        let item = ring.next()?;
+        Some(unsafe { trans(&item) })
+    }
+
     fn get_program(&mut self) -> Result<&mut UProbe> {
         // 修复3：统一程序查找的错误处理逻辑，与attach_app保持一致
-        let program = self.bpf.program_mut("frame_analyzer_ebpf")
-            .ok_or_else(|| AnalyzerError::BpfProgramError(ProgramError::NotFound))?;
+        let program = self
+            .bpf
+            .program_mut("frame_analyzer_ebpf")
+            .ok_or_else(program_not_found)?;
         let program: &mut UProbe = program.try_into()?;
 
         Ok(program)
     }
 }
+
+const unsafe fn trans(buf: &[u8]) -> FrameSignal {
+    unsafe { ptr::read_unaligned(buf.as_ptr().cast::<FrameSignal>()) }
+}
+
+fn program_not_found() -> AnalyzerError {
+    AnalyzerError::UprobeAttachError("ebpf program frame_analyzer_ebpf not found".into())
+}